@@ -0,0 +1,108 @@
+#[macro_use]
+extern crate criterion;
+extern crate bytes;
+extern crate stomp;
+extern crate tokio_io;
+
+use bytes::{Bytes, BytesMut};
+use criterion::{Criterion, Throughput};
+use stomp::codec::{legacy, Codec, ServerCodec};
+use stomp::frame::{Frame, Transmission};
+use stomp::header::StompVersion;
+use tokio_io::codec::{Decoder, Encoder};
+
+const SIZES: [usize; 3] = [1024, 64 * 1024, 4 * 1024 * 1024];
+
+fn encoded_message(size: usize) -> BytesMut {
+    let frame = Frame::message("/queue/bench", "message-1", "sub-0", vec![b'x'; size]);
+    let mut buffer = BytesMut::new();
+    ServerCodec::default().encode(Transmission::CompleteFrame(frame), &mut buffer).unwrap();
+    buffer
+}
+
+// The clone stands in for the bytes arriving from the socket
+fn decode(buffer: &BytesMut) -> Frame {
+    let mut buffer = buffer.clone();
+    match Codec::default().decode(&mut buffer) {
+        Ok(Some(Transmission::CompleteFrame(frame))) => frame,
+        _ => panic!("expected a frame"),
+    }
+}
+
+// Compares the body shared with the read buffer against copying it out, as `Vec<u8>` bodies did
+fn decode_message(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_message");
+    for &size in SIZES.iter() {
+        let buffer = encoded_message(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("shared/{}", size), |b| b.iter(|| decode(&buffer)));
+        group.bench_function(format!("copied/{}", size), |b| b.iter(|| decode(&buffer).body.to_vec()));
+    }
+    group.finish();
+}
+
+// Compares handing over an owned body with building one from a borrowed slice
+fn send_frame(c: &mut Criterion) {
+    let mut group = c.benchmark_group("send_frame");
+    for &size in SIZES.iter() {
+        let body = Bytes::from(vec![b'x'; size]);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("owned/{}", size), |b| {
+            b.iter(|| Frame::send("/queue/bench", body.clone()))
+        });
+        group.bench_function(format!("borrowed/{}", size), |b| {
+            b.iter(|| Frame::send("/queue/bench", &body[..]))
+        });
+    }
+    group.finish();
+}
+
+const CHUNK: usize = 4096;
+// The legacy parser is quadratic here, so larger frames take seconds per iteration
+const TRICKLED_SIZES: [usize; 2] = [64 * 1024, 1024 * 1024];
+
+// Feeds `encoded` to `decode` a chunk at a time, the way a large frame arrives from the socket
+fn trickle<F>(encoded: &BytesMut, mut decode: F) -> Frame
+    where F: FnMut(&mut BytesMut) -> Option<Transmission>
+{
+    let mut buffer = BytesMut::with_capacity(encoded.len());
+    for chunk in encoded.chunks(CHUNK) {
+        buffer.extend_from_slice(chunk);
+        if let Some(Transmission::CompleteFrame(frame)) = decode(&mut buffer) {
+            return frame;
+        }
+    }
+    panic!("expected a frame");
+}
+
+// Compares the streaming decoder with the nom parser it replaced, which starts again from the
+// beginning of the buffer whenever more bytes arrive
+fn trickled_message(c: &mut Criterion) {
+    let mut group = c.benchmark_group("trickled_message");
+    group.sample_size(10);
+    for &size in TRICKLED_SIZES.iter() {
+        let with_length = encoded_message(size);
+        let mut frame = Frame::message("/queue/bench", "message-1", "sub-0", vec![b'x'; size]);
+        frame.headers.retain(|header| header.get_key() != "content-length");
+        let mut without_length = BytesMut::new();
+        frame.write(&mut without_length);
+        group.throughput(Throughput::Bytes(size as u64));
+        for &(name, encoded) in [("content-length", &with_length), ("nul", &without_length)].iter() {
+            group.bench_function(format!("streaming/{}/{}", name, size), |b| {
+                b.iter(|| {
+                    let mut codec = Codec::default();
+                    trickle(encoded, |buffer| codec.decode(buffer).unwrap())
+                })
+            });
+            group.bench_function(format!("legacy/{}/{}", name, size), |b| {
+                b.iter(|| {
+                    trickle(encoded, |buffer| legacy::decode_from_server(buffer, StompVersion::Stomp_v1_2).unwrap())
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decode_message, send_frame, trickled_message);
+criterion_main!(benches);
@@ -0,0 +1,47 @@
+#![no_main]
+// However the bytes are split up on arrival, `Codec` must decode the same transmissions as it
+// does from a single read, and fail at the same point if it fails.
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use stomp::codec::Codec;
+use stomp::frame::Transmission;
+use tokio_io::codec::Decoder;
+
+type Summary = Option<(String, Vec<(String, String)>, Vec<u8>)>;
+
+fn summary(transmission: Transmission) -> Summary {
+    match transmission {
+        Transmission::HeartBeat => None,
+        Transmission::CompleteFrame(frame) => {
+            let headers = frame.headers.iter().map(|h| (h.0.clone(), h.1.clone())).collect();
+            Some((frame.command.to_string(), headers, frame.body.to_vec()))
+        },
+    }
+}
+
+// The transmissions decoded before the first error, and whether there was one
+fn decode(bytes: &[u8], chunks: &[u8]) -> (Vec<Summary>, bool) {
+    let mut codec = Codec::default();
+    let mut buffer = BytesMut::new();
+    let mut decoded = vec![];
+    let mut rest = bytes;
+    let mut chunks = chunks.iter().map(|&length| length as usize + 1).cycle();
+    while !rest.is_empty() {
+        let length = chunks.next().unwrap_or(rest.len()).min(rest.len());
+        buffer.extend(&rest[..length]);
+        rest = &rest[length..];
+        loop {
+            match codec.decode(&mut buffer) {
+                Ok(Some(transmission)) => decoded.push(summary(transmission)),
+                Ok(None) => break,
+                Err(_) => return (decoded, true),
+            }
+        }
+    }
+    (decoded, false)
+}
+
+fuzz_target!(|input: (Vec<u8>, Vec<u8>)| {
+    let (chunks, bytes) = input;
+    assert_eq!(decode(&bytes, &chunks), decode(&bytes, &[]));
+});
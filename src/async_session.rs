@@ -0,0 +1,669 @@
+// A `Session` for applications running on tokio 1.x. The connection is driven by a task spawned
+// onto the current runtime, so `Sender`s can be cloned into as many tasks as needed while the
+// `Session` itself is consumed as a `futures_core::Stream` of `SessionEvent`s.
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use bytes::BytesMut;
+use futures_core::Stream;
+use futures_util::future::{self, FutureExt, TryFutureExt};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, Sleep};
+use tokio_io::codec::Decoder;
+use codec::{Codec, SharedVersion};
+use connection::{Connection, HeartBeat};
+use error::{BrokerError, Error, Result};
+use frame::{Command, Frame, ToFrameBody, Transmission};
+use header::{self, Header, HeaderList, StompVersion};
+use option_setter::OptionSetter;
+use session::{DisconnectionReason, PendingReceiptPolicy, SessionEvent};
+use session_builder::{SessionConfig, DEFAULT_OUTBOX_CAPACITY, DEFAULT_SEND_BUFFER_CAPACITY};
+use subscription::{AckMode, AckOrNack};
+
+const GRACE_PERIOD_MULTIPLIER: u32 = 2;
+
+// Resolves once the CONNECTED frame has been received
+pub struct Connect(Pin<Box<dyn Future<Output = Result<Session>> + Send>>);
+
+impl Future for Connect {
+    type Output = Result<Session>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Session>> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+pub(crate) fn connect(config: SessionConfig) -> Connect {
+    if let Some(unsupported) = unsupported_option(&config) {
+        let error = Error::transport(format!("{} is not supported by async sessions", unsupported));
+        return Connect(Box::pin(future::ready(Err(error))));
+    }
+    if let Err(error) = config.connect_frame().check_headers() {
+        return Connect(Box::pin(future::ready(Err(error))));
+    }
+    let address = (config.host.clone(), config.port);
+    let connecting = TcpStream::connect(address)
+        .map_err(Error::from)
+        .and_then(move |stream| {
+            let (session, connected) = spawn(stream, config);
+            connected.map(move |result| match result {
+                Ok(Ok(version)) => {
+                    let mut session = session;
+                    session.sender.version = version;
+                    Ok(session)
+                },
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(Error::SessionClosed),
+            })
+        });
+    Connect(Box::pin(connecting))
+}
+
+// Options the driver does not implement, which must not be silently ignored
+fn unsupported_option(config: &SessionConfig) -> Option<&'static str> {
+    if config.failover.is_some() {
+        return Some("Failover");
+    }
+    if config.reconnect.is_some() {
+        return Some("ReconnectPolicy");
+    }
+    if config.receipt_timeout.is_some() {
+        return Some("ReceiptTimeout");
+    }
+    if config.pending_receipts != PendingReceiptPolicy::Fail {
+        return Some("PendingReceiptPolicy");
+    }
+    if config.outbox_capacity != DEFAULT_OUTBOX_CAPACITY {
+        return Some("OutboxCapacity");
+    }
+    if config.send_buffer_capacity != DEFAULT_SEND_BUFFER_CAPACITY {
+        return Some("SendBufferCapacity");
+    }
+    #[cfg(feature = "tls")]
+    {
+        if config.tls.is_some() {
+            return Some("TLS");
+        }
+    }
+    #[cfg(feature = "websocket")]
+    {
+        if config.websocket.is_some() {
+            return Some("WebSocket");
+        }
+    }
+    None
+}
+
+fn spawn(stream: TcpStream, config: SessionConfig) -> (Session, oneshot::Receiver<Result<StompVersion>>) {
+    let (requests, request_receiver) = mpsc::unbounded_channel();
+    let (events, event_receiver) = mpsc::unbounded_channel();
+    let (connected, connected_receiver) = oneshot::channel();
+    let version = SharedVersion::default();
+    let mut driver = Driver {
+        stream,
+        read_buffer: BytesMut::new(),
+        write_buffer: BytesMut::new(),
+        queued: 0,
+        written: 0,
+        flush_waiters: VecDeque::new(),
+        requests: request_receiver,
+        events,
+        connected: Some(connected),
+        heartbeat: config.heartbeat,
+        accepted_versions: config.accepted_versions(),
+        version: version.clone(),
+        codec: Codec::with_limits(version, config.limits),
+        receipts: HashMap::new(),
+        subscriptions: HashMap::new(),
+        tx_heartbeat: None,
+        rx_heartbeat: None,
+    };
+    driver.queue(Request::new(config.connect_frame()));
+    tokio::spawn(driver);
+    let session = Session {
+        sender: Sender {
+            requests,
+            next_id: Arc::new(AtomicUsize::new(0)),
+            version: StompVersion::Stomp_v1_2,
+        },
+        events: event_receiver,
+    };
+    (session, connected_receiver)
+}
+
+pub struct Session {
+    sender: Sender,
+    events: mpsc::UnboundedReceiver<SessionEvent>,
+}
+
+impl Session {
+    // A handle that can be moved into other tasks to send frames on this session
+    pub fn sender(&self) -> Sender {
+        self.sender.clone()
+    }
+    pub fn message<T: ToFrameBody>(&self, destination: &str, body_convertible: T) -> MessageBuilder {
+        self.sender.message(destination, body_convertible)
+    }
+    pub fn subscription(&self, destination: &str) -> SubscriptionBuilder {
+        self.sender.subscription(destination)
+    }
+    pub fn unsubscribe(&self, sub_id: &str) -> Flushed {
+        self.sender.unsubscribe(sub_id)
+    }
+    pub fn acknowledge_frame(&self, frame: &Frame, which: AckOrNack) -> Flushed {
+        self.sender.acknowledge_frame(frame, which)
+    }
+    pub fn disconnect(&self) -> Receipt {
+        self.sender.disconnect()
+    }
+    pub fn version(&self) -> StompVersion {
+        self.sender.version
+    }
+}
+
+impl Stream for Session {
+    type Item = SessionEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<SessionEvent>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+#[derive(Clone)]
+pub struct Sender {
+    requests: mpsc::UnboundedSender<Request>,
+    next_id: Arc<AtomicUsize>,
+    version: StompVersion,
+}
+
+impl Sender {
+    pub fn message<T: ToFrameBody>(&self, destination: &str, body_convertible: T) -> MessageBuilder {
+        MessageBuilder {
+            sender: self.clone(),
+            frame: Frame::send(destination, body_convertible),
+        }
+    }
+    pub fn subscription(&self, destination: &str) -> SubscriptionBuilder {
+        SubscriptionBuilder {
+            sender: self.clone(),
+            destination: destination.to_owned(),
+            ack_mode: AckMode::Auto,
+            headers: HeaderList::new(),
+        }
+    }
+    pub fn unsubscribe(&self, sub_id: &str) -> Flushed {
+        self.flush(Frame::unsubscribe(sub_id))
+    }
+    pub fn acknowledge_frame(&self, frame: &Frame, which: AckOrNack) -> Flushed {
+        match Frame::acknowledgement(frame, which, self.version, None) {
+            Ok(acknowledgement) => self.flush(acknowledgement),
+            Err(e) => {
+                let (notify, receiver) = oneshot::channel();
+                let _ = notify.send(Err(e));
+                Flushed { receiver }
+            }
+        }
+    }
+    // The protocol version agreed with the broker
+    pub fn version(&self) -> StompVersion {
+        self.version
+    }
+    // Resolves once the broker has confirmed the disconnection
+    pub fn disconnect(&self) -> Receipt {
+        self.receipt(format!("disconnect/{}", self.generate_id()), Frame::disconnect())
+    }
+
+    fn generate_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+    fn flush(&self, frame: Frame) -> Flushed {
+        let (notify, receiver) = oneshot::channel();
+        let mut request = Request::new(frame);
+        request.flushed = Some(notify);
+        // If the session has gone away the request is dropped and the receiver reports it
+        let _ = self.requests.send(request);
+        Flushed { receiver }
+    }
+    fn receipt(&self, id: String, mut frame: Frame) -> Receipt {
+        let (notify, receiver) = oneshot::channel();
+        frame.headers.push(Header::new("receipt", id.as_ref()));
+        let mut request = Request::new(frame);
+        request.receipt = Some((id.clone(), notify));
+        let _ = self.requests.send(request);
+        Receipt { id, receiver }
+    }
+}
+
+pub struct MessageBuilder {
+    pub sender: Sender,
+    pub frame: Frame,
+}
+
+impl MessageBuilder {
+    // Resolves once the frame has been written to the connection
+    pub fn send(self) -> Flushed {
+        self.sender.flush(self.frame)
+    }
+    // Resolves once the broker has processed the frame
+    pub fn send_with_receipt(self) -> Receipt {
+        let id = format!("message/{}", self.sender.generate_id());
+        self.sender.receipt(id, self.frame)
+    }
+    pub fn with<T>(self, option_setter: T) -> MessageBuilder
+        where T: OptionSetter<MessageBuilder>
+    {
+        option_setter.set_option(self)
+    }
+}
+
+pub struct SubscriptionBuilder {
+    pub sender: Sender,
+    pub destination: String,
+    pub ack_mode: AckMode,
+    pub headers: HeaderList,
+}
+
+impl SubscriptionBuilder {
+    // Resolves to the subscription id once the SUBSCRIBE frame has been written
+    pub fn start(self) -> Subscribed {
+        let id = format!("stomp-rs/{}", self.sender.generate_id());
+        let mut subscribe_frame = Frame::subscribe(&id, &self.destination, self.ack_mode);
+        subscribe_frame.headers.concat(&mut self.headers.clone());
+        Subscribed {
+            id: Some(id),
+            flushed: self.sender.flush(subscribe_frame),
+        }
+    }
+    pub fn with<T>(self, option_setter: T) -> SubscriptionBuilder
+        where T: OptionSetter<SubscriptionBuilder>
+    {
+        option_setter.set_option(self)
+    }
+}
+
+pub struct Flushed {
+    receiver: oneshot::Receiver<Result<()>>,
+}
+
+impl Future for Flushed {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        match Pin::new(&mut self.receiver).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(Error::SessionClosed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+pub struct Subscribed {
+    id: Option<String>,
+    flushed: Flushed,
+}
+
+impl Future for Subscribed {
+    type Output = Result<String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<String>> {
+        match Pin::new(&mut self.flushed).poll(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(self.id.take().expect("polled after completion"))),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+pub struct Receipt {
+    id: String,
+    receiver: oneshot::Receiver<Result<Frame>>,
+}
+
+impl Receipt {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Future for Receipt {
+    type Output = Result<Frame>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Frame>> {
+        match Pin::new(&mut self.receiver).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(Error::SessionClosed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+struct Request {
+    frame: Frame,
+    flushed: Option<oneshot::Sender<Result<()>>>,
+    receipt: Option<(String, oneshot::Sender<Result<Frame>>)>,
+}
+
+impl Request {
+    fn new(frame: Frame) -> Request {
+        Request {
+            frame,
+            flushed: None,
+            receipt: None,
+        }
+    }
+}
+
+struct HeartbeatTimer {
+    period: Duration,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl HeartbeatTimer {
+    fn new(period_ms: u32) -> Option<HeartbeatTimer> {
+        if period_ms == 0 {
+            return None;
+        }
+        let period = Duration::from_millis(period_ms as u64);
+        Some(HeartbeatTimer {
+            period,
+            sleep: Box::pin(tokio::time::sleep(period)),
+        })
+    }
+    fn reset(&mut self) {
+        self.sleep.as_mut().reset(Instant::now() + self.period);
+    }
+    // Restarts the timer when it fires, so that the next expiry wakes the task as well
+    fn poll_expired(&mut self, cx: &mut Context) -> bool {
+        if self.sleep.as_mut().poll(cx).is_pending() {
+            return false;
+        }
+        self.reset();
+        let _ = self.sleep.as_mut().poll(cx);
+        true
+    }
+}
+
+// Owns the connection: writes queued requests, reads and dispatches incoming frames and keeps
+// the heart-beats going until the connection ends or every handle has been dropped.
+struct Driver {
+    stream: TcpStream,
+    read_buffer: BytesMut,
+    write_buffer: BytesMut,
+    queued: u64,
+    written: u64,
+    flush_waiters: VecDeque<(u64, oneshot::Sender<Result<()>>)>,
+    requests: mpsc::UnboundedReceiver<Request>,
+    events: mpsc::UnboundedSender<SessionEvent>,
+    connected: Option<oneshot::Sender<Result<StompVersion>>>,
+    heartbeat: HeartBeat,
+    accepted_versions: Vec<StompVersion>,
+    // Shared with `codec`
+    version: SharedVersion,
+    codec: Codec,
+    receipts: HashMap<String, (Frame, oneshot::Sender<Result<Frame>>)>,
+    subscriptions: HashMap<String, (String, AckMode)>,
+    tx_heartbeat: Option<HeartbeatTimer>,
+    rx_heartbeat: Option<HeartbeatTimer>,
+}
+
+impl Driver {
+    fn queue(&mut self, request: Request) {
+        let Request { frame, flushed, receipt } = request;
+        if let Some((id, notify)) = receipt {
+            self.receipts.insert(id, (frame.clone(), notify));
+        }
+        self.track_subscription(&frame);
+        self.write(Transmission::CompleteFrame(frame));
+        if let Some(notify) = flushed {
+            self.flush_waiters.push_back((self.queued, notify));
+        }
+    }
+    fn write(&mut self, transmission: Transmission) {
+        let before = self.write_buffer.len();
+        transmission.write_for(&mut self.write_buffer, self.version.get());
+        self.queued += (self.write_buffer.len() - before) as u64;
+    }
+    fn track_subscription(&mut self, frame: &Frame) {
+        match frame.command {
+            Command::Subscribe => {
+                let id = frame.headers.get_id().map(|h| h.0.to_owned());
+                let destination = frame.headers.get_destination().map(|h| h.0.to_owned());
+                let ack_mode = match frame.headers.get_ack().map(|h| h.0) {
+                    Some("client") => AckMode::Client,
+                    Some("client-individual") => AckMode::ClientIndividual,
+                    _ => AckMode::Auto,
+                };
+                if let (Some(id), Some(destination)) = (id, destination) {
+                    self.subscriptions.insert(id, (destination, ack_mode));
+                }
+            },
+            Command::Unsubscribe => {
+                if let Some(header::Id(id)) = frame.headers.get_id() {
+                    self.subscriptions.remove(id);
+                }
+            },
+            _ => {}
+        }
+    }
+    fn emit(&mut self, event: SessionEvent) {
+        // Nobody may be listening for events any more, which is fine
+        let _ = self.events.send(event);
+    }
+
+    fn on_transmission(&mut self, transmission: Transmission) -> Option<DisconnectionReason> {
+        let frame = match transmission {
+            Transmission::HeartBeat => {
+                debug!("Received heartbeat.");
+                return None;
+            },
+            Transmission::CompleteFrame(frame) => frame,
+        };
+        debug!("Received frame: {:?}", frame);
+        match frame.command {
+            Command::Connected => return self.on_connected(frame),
+            Command::Receipt => return self.on_receipt(frame),
+            Command::Error => return self.on_error(frame),
+            Command::Message => {
+                let subscription = frame.headers.get_subscription()
+                    .and_then(|h| self.subscriptions.get(h.0))
+                    .cloned();
+                match subscription {
+                    Some((destination, ack_mode)) => self.emit(SessionEvent::Message {
+                        destination,
+                        ack_mode,
+                        frame
+                    }),
+                    None => self.emit(SessionEvent::SubscriptionlessFrame(frame)),
+                }
+            },
+            _ => self.emit(SessionEvent::UnknownFrame(frame)),
+        }
+        None
+    }
+    fn on_connected(&mut self, frame: Frame) -> Option<DisconnectionReason> {
+        let version = match Connection::select_version(&self.accepted_versions, &frame) {
+            Ok(version) => version,
+            Err(e) => return Some(DisconnectionReason::ConnectFailed(e)),
+        };
+        self.version.set(version);
+        let HeartBeat(client_tx_ms, client_rx_ms) = self.heartbeat;
+        let (server_tx_ms, server_rx_ms) = match frame.headers.get_heart_beat() {
+            Some(header::HeartBeat(tx_ms, rx_ms)) => (tx_ms, rx_ms),
+            None => (0, 0),
+        };
+        let (tx_ms, rx_ms) = Connection::select_heartbeat(client_tx_ms,
+                                                          client_rx_ms,
+                                                          server_tx_ms,
+                                                          server_rx_ms);
+        self.tx_heartbeat = HeartbeatTimer::new(tx_ms);
+        self.rx_heartbeat = HeartbeatTimer::new(rx_ms * GRACE_PERIOD_MULTIPLIER);
+        if let Some(connected) = self.connected.take() {
+            let _ = connected.send(Ok(version));
+        }
+        self.emit(SessionEvent::Connected);
+        None
+    }
+    fn on_receipt(&mut self, frame: Frame) -> Option<DisconnectionReason> {
+        let receipt_id = match frame.headers.get_receipt_id() {
+            Some(header::ReceiptId(id)) => id.to_owned(),
+            None => return None,
+        };
+        let mut disconnected = false;
+        if let Some((original, notify)) = self.receipts.remove(&receipt_id) {
+            disconnected = matches!(original.command, Command::Disconnect);
+            let _ = notify.send(Ok(frame.clone()));
+            self.emit(SessionEvent::Receipt {
+                id: receipt_id,
+                original,
+                receipt: frame
+            });
+        }
+        if disconnected {
+            return Some(DisconnectionReason::Requested);
+        }
+        None
+    }
+    fn on_error(&mut self, frame: Frame) -> Option<DisconnectionReason> {
+        if self.connected.is_some() {
+            // An ERROR in reply to CONNECT means the broker refused the session
+            return Some(DisconnectionReason::ConnectFailed(Error::Authentication(frame)));
+        }
+        self.emit(SessionEvent::ErrorFrame(frame.clone()));
+        let receipt_id = frame.headers.get_receipt_id().map(|h| h.0.to_owned());
+        let receipt = receipt_id.and_then(|id| self.receipts.remove(&id));
+        let error = BrokerError::new(frame, receipt.as_ref().map(|(original, _)| original.clone()));
+        if let Some((_, notify)) = receipt {
+            let _ = notify.send(Err(Error::Broker(Box::new(error.clone()))));
+        }
+        // The broker closes the connection after an ERROR
+        Some(DisconnectionReason::BrokerError(error))
+    }
+
+    fn poll_requests(&mut self, cx: &mut Context) -> Option<DisconnectionReason> {
+        loop {
+            match self.requests.poll_recv(cx) {
+                Poll::Ready(Some(request)) => self.queue(request),
+                // Every handle on the session has been dropped
+                Poll::Ready(None) => return Some(DisconnectionReason::Requested),
+                Poll::Pending => return None,
+            }
+        }
+    }
+    fn poll_read(&mut self, cx: &mut Context) -> Option<DisconnectionReason> {
+        let mut chunk = [0; 8 * 1024];
+        loop {
+            let mut buffer = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut self.stream).poll_read(cx, &mut buffer) {
+                Poll::Ready(Ok(())) => {},
+                Poll::Ready(Err(e)) => return Some(DisconnectionReason::RecvFailed(e.into())),
+                Poll::Pending => return None,
+            }
+            if buffer.filled().is_empty() {
+                return Some(DisconnectionReason::ClosedByOtherSide);
+            }
+            self.read_buffer.extend_from_slice(buffer.filled());
+            if let Some(ref mut rx_heartbeat) = self.rx_heartbeat {
+                rx_heartbeat.reset();
+            }
+            loop {
+                match self.codec.decode(&mut self.read_buffer) {
+                    Ok(Some(transmission)) => {
+                        if let Some(reason) = self.on_transmission(transmission) {
+                            return Some(reason);
+                        }
+                    },
+                    Ok(None) => break,
+                    Err(Error::FrameTooLarge(message)) => return Some(DisconnectionReason::FrameTooLarge(message)),
+                    Err(e) => return Some(DisconnectionReason::RecvFailed(e)),
+                }
+            }
+        }
+    }
+    fn poll_heartbeats(&mut self, cx: &mut Context) -> Option<DisconnectionReason> {
+        let rx_expired = match self.rx_heartbeat {
+            Some(ref mut rx_heartbeat) => rx_heartbeat.poll_expired(cx),
+            None => false,
+        };
+        if rx_expired {
+            return Some(DisconnectionReason::HeartbeatTimeout);
+        }
+        let tx_expired = match self.tx_heartbeat {
+            Some(ref mut tx_heartbeat) => tx_heartbeat.poll_expired(cx),
+            None => false,
+        };
+        if tx_expired {
+            debug!("Sending heartbeat");
+            self.write(Transmission::HeartBeat);
+        }
+        None
+    }
+    fn poll_write(&mut self, cx: &mut Context) -> Option<DisconnectionReason> {
+        while !self.write_buffer.is_empty() {
+            match Pin::new(&mut self.stream).poll_write(cx, &self.write_buffer) {
+                Poll::Ready(Ok(0)) => {
+                    let error = io::Error::new(io::ErrorKind::WriteZero, "connection closed");
+                    return Some(DisconnectionReason::SendFailed(error.into()));
+                },
+                Poll::Ready(Ok(n)) => {
+                    self.write_buffer.advance(n);
+                    self.written += n as u64;
+                },
+                Poll::Ready(Err(e)) => return Some(DisconnectionReason::SendFailed(e.into())),
+                Poll::Pending => break,
+            }
+        }
+        while self.flush_waiters.front().is_some_and(|&(end, _)| end <= self.written) {
+            if let Some((_, notify)) = self.flush_waiters.pop_front() {
+                let _ = notify.send(Ok(()));
+            }
+        }
+        None
+    }
+    fn poll_connection(&mut self, cx: &mut Context) -> Option<DisconnectionReason> {
+        self.poll_requests(cx)
+            .or_else(|| self.poll_read(cx))
+            .or_else(|| self.poll_heartbeats(cx))
+            .or_else(|| self.poll_write(cx))
+    }
+    fn finish(&mut self, reason: DisconnectionReason) {
+        info!("Disconnected.");
+        if let Some(connected) = self.connected.take() {
+            // Nobody has seen the session yet, so the failure goes to `start` instead
+            let error = match reason {
+                DisconnectionReason::RecvFailed(e) |
+                DisconnectionReason::ConnectFailed(e) |
+                DisconnectionReason::SendFailed(e) => e,
+                DisconnectionReason::FrameTooLarge(message) => Error::FrameTooLarge(message),
+                DisconnectionReason::BrokerError(error) => Error::Broker(Box::new(error)),
+                DisconnectionReason::HeartbeatTimeout => Error::HeartbeatTimeout,
+                DisconnectionReason::ClosedByOtherSide |
+                DisconnectionReason::Requested => Error::SessionClosed,
+            };
+            let _ = connected.send(Err(error));
+            return;
+        }
+        self.emit(SessionEvent::Disconnected(reason));
+    }
+}
+
+impl Future for Driver {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        match self.poll_connection(cx) {
+            // Outstanding receipts and flushes fail with `SessionClosed` as the driver is dropped
+            Some(reason) => {
+                self.finish(reason);
+                Poll::Ready(())
+            },
+            None => Poll::Pending,
+        }
+    }
+}
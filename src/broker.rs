@@ -0,0 +1,604 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use futures::{Future, Sink, Stream};
+use futures::sync::{mpsc, oneshot};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle, Interval};
+use tokio_io::AsyncRead;
+use codec::{ServerCodec, SharedVersion};
+use error::Error;
+use connection::{Connection, HeartBeat};
+use frame::{Command, Frame, Transmission};
+use header::{Header, HeaderList, StompVersion};
+use subscription::AckMode;
+
+const GRACE_PERIOD_MULTIPLIER: u32 = 2;
+
+#[derive(Clone, Copy)]
+pub struct BrokerConfig {
+    pub heartbeat: HeartBeat,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> BrokerConfig {
+        BrokerConfig {
+            heartbeat: HeartBeat(0, 0),
+        }
+    }
+}
+
+// A small in-process broker for exercising clients in tests. Destinations
+// starting with `/topic/` fan out to every current subscriber; everything
+// else is treated as a queue.
+pub struct Broker {
+    address: SocketAddr,
+    state: Arc<Mutex<BrokerState>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Broker {
+    pub fn start() -> io::Result<Broker> {
+        Broker::start_with_config(BrokerConfig::default())
+    }
+
+    pub fn start_with_config(config: BrokerConfig) -> io::Result<Broker> {
+        let listener = StdTcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(BrokerState::new(config)));
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (ready_tx, ready_rx) = ::std::sync::mpsc::channel();
+        let thread_state = state.clone();
+        let thread = thread::spawn(move || {
+            let mut core = match Core::new() {
+                Ok(core) => core,
+                Err(e) => return ready_tx.send(Err(e)).unwrap_or(()),
+            };
+            let hdl = core.handle();
+            let listener = match TcpListener::from_listener(listener, &address, &hdl) {
+                Ok(listener) => listener,
+                Err(e) => return ready_tx.send(Err(e)).unwrap_or(()),
+            };
+            let _ = ready_tx.send(Ok(()));
+            let server = listener.incoming().for_each(move |(socket, peer)| {
+                debug!("Broker accepted connection from {}", peer);
+                let version = SharedVersion::default();
+                serve(socket.framed(ServerCodec::new(version.clone())), version, thread_state.clone(), hdl.clone());
+                Ok(())
+            });
+            let _ = core.run(server.select2(shutdown_rx));
+        });
+        ready_rx.recv()
+            .unwrap_or_else(|_| Err(io::Error::other("broker thread failed to start")))?;
+        Ok(Broker {
+            address,
+            state,
+            shutdown: Some(shutdown_tx),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn port(&self) -> u16 {
+        self.address.port()
+    }
+
+    // Messages waiting on a queue that have not been delivered to any subscriber
+    pub fn pending(&self, destination: &str) -> Vec<Frame> {
+        self.lock().queues
+            .get(destination)
+            .map(|queue| queue.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Messages delivered to a subscriber that are still awaiting an ACK or NACK
+    pub fn unacknowledged(&self, destination: &str) -> Vec<Frame> {
+        let state = self.lock();
+        let mut deliveries: Vec<&Delivery> = state.connections
+            .values()
+            .flat_map(|connection| connection.deliveries.iter())
+            .filter(|delivery| delivery.destination == destination)
+            .collect();
+        deliveries.sort_by_key(|delivery| delivery.sequence);
+        deliveries.into_iter().map(|delivery| delivery.message.clone()).collect()
+    }
+
+    pub fn subscriber_count(&self, destination: &str) -> usize {
+        self.lock().connections
+            .values()
+            .flat_map(|connection| connection.subscriptions.values())
+            .filter(|subscription| subscription.destination == destination)
+            .count()
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.lock().connections.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BrokerState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve<T>(transport: T, version: SharedVersion, state: Arc<Mutex<BrokerState>>, hdl: Handle)
+    where T: Stream<Item = Transmission, Error = Error>
+        + Sink<SinkItem = Transmission, SinkError = Error> + 'static
+{
+    let (sink, stream) = transport.split();
+    let (outbound_tx, outbound_rx) = mpsc::unbounded();
+    let id = lock(&state).add_connection(outbound_tx, version);
+
+    hdl.spawn(outbound_rx
+        .map_err(|_| Error::transport("outbound channel failed"))
+        .forward(sink)
+        .then(|_| Ok(())));
+
+    let reader_state = state.clone();
+    let reader_hdl = hdl.clone();
+    let reader = stream
+        .for_each(move |transmission| {
+            let heartbeat = {
+                let mut state = lock(&reader_state);
+                state.on_transmission(id, transmission);
+                state.take_heartbeat(id)
+            };
+            if let Some((tx_ms, rx_ms)) = heartbeat {
+                spawn_heartbeats(id, tx_ms, rx_ms, reader_state.clone(), &reader_hdl);
+            }
+            Ok(())
+        })
+        .then(move |result| {
+            if let Err(e) = result {
+                debug!("Broker connection {} failed: {}", id, e);
+            }
+            lock(&state).remove_connection(id);
+            Ok(())
+        });
+    hdl.spawn(reader);
+}
+
+fn spawn_heartbeats(id: usize, tx_ms: u32, rx_ms: u32, state: Arc<Mutex<BrokerState>>, hdl: &Handle) {
+    if tx_ms > 0 {
+        if let Ok(interval) = Interval::new(Duration::from_millis(tx_ms as u64), hdl) {
+            let state = state.clone();
+            hdl.spawn(interval
+                .map_err(|_| ())
+                .for_each(move |_| lock(&state).send_heartbeat(id)));
+        }
+    }
+    if rx_ms > 0 {
+        if let Ok(interval) = Interval::new(Duration::from_millis(rx_ms as u64), hdl) {
+            let limit = Duration::from_millis((rx_ms * GRACE_PERIOD_MULTIPLIER) as u64);
+            hdl.spawn(interval
+                .map_err(|_| ())
+                .for_each(move |_| lock(&state).check_heartbeat(id, limit)));
+        }
+    }
+}
+
+fn lock(state: &Arc<Mutex<BrokerState>>) -> MutexGuard<'_, BrokerState> {
+    state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn is_auto(ack_mode: AckMode) -> bool {
+    matches!(ack_mode, AckMode::Auto)
+}
+
+fn is_topic(destination: &str) -> bool {
+    destination.starts_with("/topic/")
+}
+
+struct BrokerSubscription {
+    destination: String,
+    ack_mode: AckMode,
+}
+
+struct Delivery {
+    sequence: u64,
+    ack_id: String,
+    message_id: String,
+    subscription: String,
+    destination: String,
+    message: Frame,
+}
+
+struct ClientConnection {
+    outbound: mpsc::UnboundedSender<Transmission>,
+    // Shared with the connection's codec
+    version: SharedVersion,
+    subscriptions: HashMap<String, BrokerSubscription>,
+    deliveries: Vec<Delivery>,
+    transactions: HashMap<String, Vec<Frame>>,
+    last_received: Instant,
+    pending_heartbeat: Option<(u32, u32)>,
+}
+
+struct BrokerState {
+    config: BrokerConfig,
+    next_connection_id: usize,
+    next_message_id: u64,
+    next_delivery: u64,
+    connections: HashMap<usize, ClientConnection>,
+    queues: HashMap<String, VecDeque<Frame>>,
+    next_consumer: HashMap<String, usize>,
+}
+
+impl BrokerState {
+    fn new(config: BrokerConfig) -> BrokerState {
+        BrokerState {
+            config,
+            next_connection_id: 0,
+            next_message_id: 0,
+            next_delivery: 0,
+            connections: HashMap::new(),
+            queues: HashMap::new(),
+            next_consumer: HashMap::new(),
+        }
+    }
+
+    fn add_connection(&mut self, outbound: mpsc::UnboundedSender<Transmission>, version: SharedVersion) -> usize {
+        let id = self.next_connection_id;
+        self.next_connection_id += 1;
+        self.connections.insert(id, ClientConnection {
+            outbound,
+            version,
+            subscriptions: HashMap::new(),
+            deliveries: Vec::new(),
+            transactions: HashMap::new(),
+            last_received: Instant::now(),
+            pending_heartbeat: None,
+        });
+        id
+    }
+
+    // Dropping the connection's sender closes the socket once its queued
+    // frames have been written. Anything it had not acknowledged is requeued.
+    fn remove_connection(&mut self, id: usize) {
+        if let Some(connection) = self.connections.remove(&id) {
+            debug!("Broker connection {} closed", id);
+            self.requeue(connection.deliveries);
+        }
+    }
+
+    fn take_heartbeat(&mut self, id: usize) -> Option<(u32, u32)> {
+        self.connections.get_mut(&id).and_then(|c| c.pending_heartbeat.take())
+    }
+
+    fn send_heartbeat(&mut self, id: usize) -> Result<(), ()> {
+        match self.connections.get(&id) {
+            Some(connection) => connection.outbound.unbounded_send(Transmission::HeartBeat).map_err(|_| ()),
+            None => Err(()),
+        }
+    }
+
+    fn check_heartbeat(&mut self, id: usize, limit: Duration) -> Result<(), ()> {
+        let expired = match self.connections.get(&id) {
+            Some(connection) => connection.last_received.elapsed() > limit,
+            None => return Err(()),
+        };
+        if expired {
+            warn!("Broker connection {} missed its heart-beats", id);
+            self.remove_connection(id);
+            return Err(());
+        }
+        Ok(())
+    }
+
+    fn send(&self, id: usize, frame: Frame) {
+        if let Some(connection) = self.connections.get(&id) {
+            let _ = connection.outbound.unbounded_send(Transmission::CompleteFrame(frame));
+        }
+    }
+
+    fn on_transmission(&mut self, id: usize, transmission: Transmission) {
+        match self.connections.get_mut(&id) {
+            Some(connection) => connection.last_received = Instant::now(),
+            None => return,
+        }
+        if let Transmission::CompleteFrame(frame) = transmission {
+            debug!("Broker received frame: {:?}", frame);
+            let receipt = frame.headers.get_receipt().map(|r| r.0.to_owned());
+            let command = frame.command;
+            match self.on_frame(id, frame) {
+                Ok(()) => {
+                    if let Some(receipt) = receipt {
+                        self.send(id, Frame::receipt(&receipt));
+                    }
+                    if let Command::Disconnect = command {
+                        self.remove_connection(id);
+                    }
+                },
+                Err(message) => self.send_error(id, &message, receipt),
+            }
+        }
+    }
+
+    fn send_error(&mut self, id: usize, message: &str, receipt: Option<String>) {
+        warn!("Broker rejecting frame from connection {}: {}", id, message);
+        let mut error_frame = Frame::error(message, message.as_bytes());
+        if let Some(receipt) = receipt {
+            error_frame.headers.push(Header::new("receipt-id", &receipt));
+        }
+        self.send(id, error_frame);
+        self.remove_connection(id);
+    }
+
+    fn on_frame(&mut self, id: usize, frame: Frame) -> Result<(), String> {
+        match frame.command {
+            Command::Connect | Command::Stomp => self.on_connect(id, &frame),
+            Command::Disconnect => Ok(()),
+            Command::Subscribe => self.on_subscribe(id, &frame),
+            Command::Unsubscribe => self.on_unsubscribe(id, &frame),
+            Command::Begin => {
+                let transaction = required(&frame, "transaction")?;
+                let connection = self.connection(id)?;
+                if connection.transactions.contains_key(&transaction) {
+                    return Err(format!("transaction '{}' already started", transaction));
+                }
+                connection.transactions.insert(transaction, Vec::new());
+                Ok(())
+            },
+            Command::Commit => {
+                let transaction = required(&frame, "transaction")?;
+                let frames = self.connection(id)?.transactions.remove(&transaction)
+                    .ok_or_else(|| format!("unknown transaction '{}'", transaction))?;
+                for frame in frames {
+                    self.apply(id, frame)?;
+                }
+                Ok(())
+            },
+            Command::Abort => {
+                let transaction = required(&frame, "transaction")?;
+                self.connection(id)?.transactions.remove(&transaction)
+                    .map(|_| ())
+                    .ok_or_else(|| format!("unknown transaction '{}'", transaction))
+            },
+            Command::Send | Command::Ack | Command::Nack => {
+                let transaction = frame.headers.get_transaction().map(|t| t.0.to_owned());
+                match transaction {
+                    Some(transaction) => {
+                        self.connection(id)?.transactions.get_mut(&transaction)
+                            .ok_or_else(|| format!("unknown transaction '{}'", transaction))?
+                            .push(frame);
+                        Ok(())
+                    },
+                    None => self.apply(id, frame),
+                }
+            },
+            command => Err(format!("unexpected {} frame", command)),
+        }
+    }
+
+    fn connection(&mut self, id: usize) -> Result<&mut ClientConnection, String> {
+        self.connections.get_mut(&id).ok_or_else(|| "connection closed".to_owned())
+    }
+
+    fn apply(&mut self, id: usize, frame: Frame) -> Result<(), String> {
+        match frame.command {
+            Command::Send => self.on_send(frame),
+            Command::Ack => self.on_ack(id, &frame, false),
+            Command::Nack => self.on_ack(id, &frame, true),
+            command => Err(format!("{} frames cannot be part of a transaction", command)),
+        }
+    }
+
+    fn on_connect(&mut self, id: usize, frame: &Frame) -> Result<(), String> {
+        let version = match frame.headers.get_header("accept-version") {
+            None => "1.0",
+            Some(header) => {
+                let accepted: Vec<&str> = header.get_value().split(',').map(str::trim).collect();
+                match ["1.2", "1.1", "1.0"].iter().find(|v| accepted.contains(v)) {
+                    Some(version) => version,
+                    None => return Err("supported protocol versions are 1.0,1.1,1.2".to_owned()),
+                }
+            },
+        };
+        let (client_tx_ms, client_rx_ms) = match frame.headers.get_heart_beat() {
+            Some(::header::HeartBeat(tx_ms, rx_ms)) => (tx_ms, rx_ms),
+            None => (0, 0),
+        };
+        let HeartBeat(server_tx_ms, server_rx_ms) = self.config.heartbeat;
+        // Selected from the client's perspective, so its rx is our tx
+        let (agreed_rx_ms, agreed_tx_ms) = Connection::select_heartbeat(client_tx_ms,
+                                                                        client_rx_ms,
+                                                                        server_tx_ms,
+                                                                        server_rx_ms);
+        let connection = self.connection(id)?;
+        connection.pending_heartbeat = Some((agreed_tx_ms, agreed_rx_ms));
+        connection.version.set(version.parse().unwrap_or(StompVersion::Stomp_v1_0));
+        let mut connected_frame = Frame::connected(version, server_tx_ms, server_rx_ms);
+        connected_frame.headers.push(Header::new("session", &format!("session-{}", id)));
+        connected_frame.headers.push(Header::new("server", "stomp-rs-broker"));
+        self.send(id, connected_frame);
+        Ok(())
+    }
+
+    fn on_subscribe(&mut self, id: usize, frame: &Frame) -> Result<(), String> {
+        let destination = required(frame, "destination")?;
+        let subscription_id = required(frame, "id")?;
+        let ack_mode = match frame.headers.get_ack().map(|a| a.0) {
+            None | Some("auto") => AckMode::Auto,
+            Some("client") => AckMode::Client,
+            Some("client-individual") => AckMode::ClientIndividual,
+            Some(other) => return Err(format!("unknown ack mode '{}'", other)),
+        };
+        self.connection(id)?.subscriptions.insert(subscription_id, BrokerSubscription {
+            destination: destination.clone(),
+            ack_mode,
+        });
+        self.dispatch(&destination);
+        Ok(())
+    }
+
+    fn on_unsubscribe(&mut self, id: usize, frame: &Frame) -> Result<(), String> {
+        let subscription_id = required(frame, "id")?;
+        let connection = self.connection(id)?;
+        connection.subscriptions.remove(&subscription_id)
+            .ok_or_else(|| format!("unknown subscription '{}'", subscription_id))?;
+        let (abandoned, kept) = connection.deliveries
+            .drain(..)
+            .partition(|delivery| delivery.subscription == subscription_id);
+        connection.deliveries = kept;
+        self.requeue(abandoned);
+        Ok(())
+    }
+
+    fn on_send(&mut self, frame: Frame) -> Result<(), String> {
+        let destination = required(&frame, "destination")?;
+        let message_id = format!("message-{}", self.next_message_id);
+        self.next_message_id += 1;
+        let mut headers = HeaderList::new();
+        for header in frame.headers.iter() {
+            match header.get_key() {
+                "receipt" | "transaction" | "content-length" | "message-id" => {},
+                _ => headers.push(header.clone()),
+            }
+        }
+        headers.push(Header::new("message-id", &message_id));
+        headers.push(Header::new("content-length", &frame.body.len().to_string()));
+        let message = Frame {
+            command: Command::Message,
+            headers,
+            body: frame.body,
+        };
+        if is_topic(&destination) {
+            for (connection, subscription) in self.subscribers(&destination) {
+                self.deliver(connection, &subscription, message.clone());
+            }
+        } else {
+            self.queues.entry(destination.clone()).or_default().push_back(message);
+            self.dispatch(&destination);
+        }
+        Ok(())
+    }
+
+    fn on_ack(&mut self, id: usize, frame: &Frame, nack: bool) -> Result<(), String> {
+        let ack_id = frame.headers.get_id().map(|i| i.0.to_owned());
+        let message_id = frame.headers.get_message_id().map(|m| m.0.to_owned());
+        let connection = self.connection(id)?;
+        let position = connection.deliveries.iter()
+            .position(|d| Some(&d.ack_id) == ack_id.as_ref() || Some(&d.message_id) == message_id.as_ref())
+            .ok_or_else(|| "no unacknowledged message matches this frame".to_owned())?;
+        let subscription = connection.deliveries[position].subscription.clone();
+        let cumulative = matches!(connection.subscriptions.get(&subscription).map(|s| s.ack_mode),
+                                  Some(AckMode::Client));
+        let sequence = connection.deliveries[position].sequence;
+        let (settled, kept) = connection.deliveries
+            .drain(..)
+            .partition(|d| {
+                d.sequence == sequence || (cumulative && d.subscription == subscription && d.sequence < sequence)
+            });
+        connection.deliveries = kept;
+        if nack {
+            self.requeue(settled);
+        }
+        Ok(())
+    }
+
+    // Returns messages to the front of their queues in their original order.
+    // Topic messages are dropped.
+    fn requeue(&mut self, mut deliveries: Vec<Delivery>) {
+        deliveries.sort_by_key(|delivery| delivery.sequence);
+        let mut destinations = Vec::new();
+        for delivery in deliveries.into_iter().rev() {
+            if is_topic(&delivery.destination) {
+                continue;
+            }
+            let mut message = delivery.message;
+            message.headers.retain(|h| h.get_key() != "subscription" && h.get_key() != "ack");
+            self.queues.entry(delivery.destination.clone()).or_default().push_front(message);
+            if !destinations.contains(&delivery.destination) {
+                destinations.push(delivery.destination);
+            }
+        }
+        for destination in destinations {
+            self.dispatch(&destination);
+        }
+    }
+
+    fn subscribers(&self, destination: &str) -> Vec<(usize, String)> {
+        let mut subscribers: Vec<(usize, String)> = self.connections.iter()
+            .flat_map(|(id, connection)| {
+                connection.subscriptions.iter()
+                    .filter(|&(_, s)| s.destination == destination)
+                    .map(move |(sub_id, _)| (*id, sub_id.clone()))
+            })
+            .collect();
+        subscribers.sort();
+        subscribers
+    }
+
+    // Hands queued messages to the destination's subscribers in turn
+    fn dispatch(&mut self, destination: &str) {
+        if is_topic(destination) {
+            return;
+        }
+        let subscribers = self.subscribers(destination);
+        if subscribers.is_empty() {
+            return;
+        }
+        loop {
+            let message = match self.queues.get_mut(destination).and_then(|q| q.pop_front()) {
+                Some(message) => message,
+                None => return,
+            };
+            let next = self.next_consumer.entry(destination.to_owned()).or_insert(0);
+            let (connection, subscription) = subscribers[*next % subscribers.len()].clone();
+            *next = next.wrapping_add(1);
+            self.deliver(connection, &subscription, message);
+        }
+    }
+
+    fn deliver(&mut self, id: usize, subscription: &str, mut message: Frame) {
+        let sequence = self.next_delivery;
+        self.next_delivery += 1;
+        let ack_id = format!("ack-{}", sequence);
+        let connection = match self.connections.get_mut(&id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let ack_mode = match connection.subscriptions.get(subscription) {
+            Some(subscription) => subscription.ack_mode,
+            None => return,
+        };
+        message.headers.push(Header::new("subscription", subscription));
+        if !is_auto(ack_mode) {
+            message.headers.push(Header::new("ack", &ack_id));
+            let destination = message.headers.get_destination().map(|d| d.0.to_owned()).unwrap_or_default();
+            let message_id = message.headers.get_message_id().map(|m| m.0.to_owned()).unwrap_or_default();
+            connection.deliveries.push(Delivery {
+                sequence,
+                ack_id,
+                message_id,
+                subscription: subscription.to_owned(),
+                destination,
+                message: message.clone(),
+            });
+        }
+        let _ = connection.outbound.unbounded_send(Transmission::CompleteFrame(message));
+    }
+}
+
+fn required(frame: &Frame, key: &str) -> Result<String, String> {
+    frame.headers.get_header(key)
+        .map(|h| h.get_value().to_owned())
+        .ok_or_else(|| format!("{} frame is missing the '{}' header", frame.command, key))
+}
@@ -1,127 +1,289 @@
-use header::{Header, HeaderList};
-use frame::{Frame, Transmission};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use error::Error;
+use header::{Header, StompVersion};
+use frame::Transmission;
+#[cfg(test)]
+use frame::Frame;
 use bytes::BytesMut;
-use frame::Command;
 use tokio_io::codec::{Encoder, Decoder};
-use nom::{line_ending, anychar};
-
-named!(parse_server_command(&[u8]) -> Command,
-       alt!(
-           map!(tag!("CONNECTED"), |_| Command::Connected) |
-           map!(tag!("MESSAGE"), |_| Command::Message) |
-           map!(tag!("RECEIPT"), |_| Command::Receipt) |
-           map!(tag!("ERROR"), |_| Command::Error)
-       )
-);
-named!(parse_header_character(&[u8]) -> char,
-       alt!(
-           complete!(map!(tag!("\\n"), |_| '\n')) |
-           complete!(map!(tag!("\\r"), |_| '\r')) |
-           complete!(map!(tag!("\\c"), |_| ':')) |
-           complete!(map!(tag!("\\\\"), |_| '\\')) |
-           anychar
-       )
-);
-named!(parse_header(&[u8]) -> Header,
-       map!(
-           do_parse!(
-               k: flat_map!(is_not!(":\r\n"), many1!(parse_header_character)) >>
-               tag!(":") >>
-               v: flat_map!(is_not!("\r\n"), many1!(parse_header_character))>>
-               line_ending >>
-               (k, v)
-           ),
-           |(k, v)| {
-               Header::new_raw(k.into_iter().collect::<String>(), v.into_iter().collect::<String>())
-           }
-       )
-);
-fn get_body<'a, 'b>(bytes: &'a [u8], headers: &'b [Header]) -> ::nom::IResult<&'a [u8], &'a [u8]> {
-    let mut content_length = None;
-    for header in headers {
-        if header.0 == "content-length" {
-            trace!("found content-length header");
-            match header.1.parse::<u32>() {
-                Ok(value) => content_length = Some(value),
-                Err(error) => warn!("failed to parse content-length header: {}", error)
-            }
+use decoder::{FrameDecoder, Peer};
+
+#[cfg(feature = "legacy-parser")]
+#[doc(hidden)]
+pub mod legacy;
+
+// `raw` is the encoded transmission, reported if it holds an escape sequence `version` does not define
+pub(crate) fn unescape_headers(transmission: &mut Transmission,
+                               version: StompVersion,
+                               raw: &[u8])
+                               -> Result<(), Error> {
+    let frame = match *transmission {
+        Transmission::CompleteFrame(ref mut frame) if frame.command.escapes_headers() => frame,
+        _ => return Ok(()),
+    };
+    for header in frame.headers.headers.iter_mut() {
+        let unescaped = Header::decode_value_for(&header.0, version)
+            .and_then(|key| Ok((key, Header::decode_value_for(&header.1, version)?)));
+        match unescaped {
+            Ok((key, value)) => {
+                header.0 = key;
+                header.1 = value;
+            },
+            Err(sequence) => return Err(Error::Parse {
+                message: format!("undefined escape sequence '{}' in STOMP {} header", sequence, version),
+                bytes: raw.to_vec(),
+            }),
+        }
+    }
+    Ok(())
+}
+
+// The protocol version of a connection, shared between its codec and whichever side handles
+// the CONNECT/CONNECTED exchange so that header escaping follows the negotiation.
+// Until a version has been agreed the STOMP 1.2 rules apply.
+#[derive(Clone, Debug)]
+pub struct SharedVersion(Arc<AtomicUsize>);
+
+impl SharedVersion {
+    pub fn new(version: StompVersion) -> SharedVersion {
+        SharedVersion(Arc::new(AtomicUsize::new(version as usize)))
+    }
+    pub fn get(&self) -> StompVersion {
+        match self.0.load(Ordering::Relaxed) {
+            0 => StompVersion::Stomp_v1_0,
+            1 => StompVersion::Stomp_v1_1,
+            _ => StompVersion::Stomp_v1_2,
+        }
+    }
+    pub fn set(&self, version: StompVersion) {
+        self.0.store(version as usize, Ordering::Relaxed);
+    }
+}
+
+impl Default for SharedVersion {
+    fn default() -> SharedVersion {
+        SharedVersion::new(StompVersion::Stomp_v1_2)
+    }
+}
+
+// The most a codec will buffer for each part of a frame before giving up on the connection.
+// Nothing is limited by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameLimits {
+    // The command line or any one header line, excluding its EOL
+    pub max_line_length: usize,
+    pub max_headers: usize,
+    // Applies to the announced content-length as well as to bodies that are terminated by NUL
+    pub max_body_length: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> FrameLimits {
+        FrameLimits {
+            max_line_length: usize::MAX,
+            max_headers: usize::MAX,
+            max_body_length: usize::MAX,
         }
     }
-    if let Some(content_length) = content_length {
-        trace!("using content-length header: {}", content_length);
-        take!(bytes, content_length)
-    }
-    else {
-        trace!("using many0 method to parse body");
-        map!(bytes,
-            many0!(is_not!("\0")),
-            |body| {
-                if body.len() == 0 {
-                    &[]
-                } else {
-                    body.into_iter().nth(0).unwrap()
-                }
-            }
-        )
-    }
-}
-named!(parse_frame(&[u8]) -> Frame,
-       map!(
-           do_parse!(
-               cmd: parse_server_command >>
-               line_ending >>
-               headers: many0!(parse_header) >>
-               line_ending >>
-               body: call!(get_body, &headers) >>
-               tag!("\0") >>
-               (cmd, headers, body)
-           ),
-           |(cmd, headers, body)| {
-               Frame {
-                   command: cmd,
-                   headers: HeaderList { headers },
-                   body: body.into()
-               }
-           }
-       )
-);
-named!(parse_transmission(&[u8]) -> Transmission,
-       alt!(
-           map!(many1!(line_ending), |_| Transmission::HeartBeat) |
-           map!(parse_frame, |f| Transmission::CompleteFrame(f))
-       )
-);
-pub struct Codec;
+}
+
+pub struct MaxLineLength(pub usize);
+pub struct MaxHeaders(pub usize);
+pub struct MaxBodyLength(pub usize);
+
+#[derive(Clone, Debug)]
+pub struct Codec {
+    version: SharedVersion,
+    decoder: FrameDecoder,
+}
+
+impl Codec {
+    pub fn new(version: SharedVersion) -> Codec {
+        Codec::with_limits(version, FrameLimits::default())
+    }
+    // Frames that exceed `limits` fail to decode with `Error::FrameTooLarge`
+    pub fn with_limits(version: SharedVersion, limits: FrameLimits) -> Codec {
+        let mut decoder = FrameDecoder::new(Peer::Server);
+        decoder.limits = limits;
+        Codec {
+            version,
+            decoder,
+        }
+    }
+    // Encodes without taking the transmission, for transports that may have to hand it back
+    pub(crate) fn write(&self, item: &Transmission, buffer: &mut BytesMut) -> Result<(), Error> {
+        if let Transmission::CompleteFrame(ref frame) = *item {
+            frame.check_headers()?;
+        }
+        item.write_for(buffer, self.version.get());
+        Ok(())
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Codec {
+        Codec::new(SharedVersion::default())
+    }
+}
 
 impl Encoder for Codec {
     type Item = Transmission;
-    type Error = ::std::io::Error;
-    fn encode(&mut self, item: Transmission, buffer: &mut BytesMut) -> Result<(), ::std::io::Error> {
-        item.write(buffer);
-        Ok(())
+    type Error = Error;
+    fn encode(&mut self, item: Transmission, buffer: &mut BytesMut) -> Result<(), Error> {
+        self.write(&item, buffer)
     }
 }
 impl Decoder for Codec {
     type Item = Transmission;
-    type Error = ::std::io::Error;
+    type Error = Error;
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Transmission>, ::std::io::Error> {
-        use nom::IResult;
-        use std::io::{Error, ErrorKind};
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Transmission>, Error> {
+        self.decoder.decode(src, self.version.get())
+    }
+}
 
-        trace!("decoding data: {:?}", src);
-        let (point, data) = match parse_transmission(src) {
-            IResult::Done(rest, data) => {
-                (rest.len(), data)
-            },
-            IResult::Error(e) => {
-                warn!("parse error: {:?}", e);
-                return Err(Error::new(ErrorKind::Other, format!("parse error: {}", e)));
-            },
-            IResult::Incomplete(_) => return Ok(None)
-        };
-        let len = src.len().saturating_sub(point);
-        src.split_to(len);
-        Ok(Some(data))
+// The server side of a connection: decodes the commands a client may send
+// and encodes the frames a server replies with
+#[derive(Clone, Debug)]
+pub struct ServerCodec {
+    version: SharedVersion,
+    decoder: FrameDecoder,
+}
+
+impl ServerCodec {
+    pub fn new(version: SharedVersion) -> ServerCodec {
+        ServerCodec {
+            version,
+            decoder: FrameDecoder::new(Peer::Client),
+        }
+    }
+}
+
+impl Default for ServerCodec {
+    fn default() -> ServerCodec {
+        ServerCodec::new(SharedVersion::default())
+    }
+}
+
+impl Encoder for ServerCodec {
+    type Item = Transmission;
+    type Error = Error;
+    fn encode(&mut self, item: Transmission, buffer: &mut BytesMut) -> Result<(), Error> {
+        if let Transmission::CompleteFrame(ref frame) = item {
+            frame.check_headers()?;
+        }
+        item.write_for(buffer, self.version.get());
+        Ok(())
+    }
+}
+impl Decoder for ServerCodec {
+    type Item = Transmission;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Transmission>, Error> {
+        self.decoder.decode(src, self.version.get())
+    }
+}
+
+#[cfg(test)]
+fn decode_frame<D: Decoder<Item = Transmission>>(codec: &mut D, bytes: &[u8]) -> Option<Frame> {
+    let mut buffer = BytesMut::from(bytes);
+    match codec.decode(&mut buffer) {
+        Ok(Some(Transmission::CompleteFrame(frame))) => Some(frame),
+        _ => None,
+    }
+}
+
+#[test]
+fn parse_errors_carry_the_offending_bytes() {
+    let mut buffer = BytesMut::from(&b"BOGUS\n\n\0"[..]);
+    match Codec::default().decode(&mut buffer) {
+        Err(Error::Parse { bytes, .. }) => assert_eq!(bytes, b"BOGUS\n\n\0"),
+        _ => panic!("expected a parse error"),
+    }
+}
+
+#[test]
+fn server_codec_decodes_every_client_command() {
+    let commands = ["SEND", "SUBSCRIBE", "UNSUBSCRIBE", "BEGIN", "COMMIT", "ABORT",
+                    "ACK", "NACK", "DISCONNECT", "CONNECT", "STOMP"];
+    for command in commands.iter() {
+        let bytes = format!("{}\nreceipt:77\n\n\0", command);
+        let frame = decode_frame(&mut ServerCodec::default(), bytes.as_bytes())
+            .unwrap_or_else(|| panic!("failed to decode {}", command));
+        assert_eq!(frame.command.as_str(), *command);
+        assert_eq!(frame.headers.get_receipt().unwrap().0, "77");
+    }
+}
+
+#[test]
+fn each_codec_rejects_the_other_side_s_commands() {
+    assert!(decode_frame(&mut ServerCodec::default(), b"MESSAGE\nsubscription:0\n\n\0").is_none());
+    assert!(decode_frame(&mut Codec::default(), b"SEND\ndestination:/queue/a\n\n\0").is_none());
+}
+
+#[test]
+fn server_frames_round_trip_to_client_codec() {
+    let mut buffer = BytesMut::new();
+    let frame = Frame::message("/queue/a", "message-1", "sub-0", b"hello");
+    ServerCodec::default().encode(Transmission::CompleteFrame(frame), &mut buffer).unwrap();
+    let decoded = decode_frame(&mut Codec::default(), &buffer).unwrap();
+    assert_eq!(decoded.command.as_str(), "MESSAGE");
+    assert_eq!(decoded.headers.get_destination().unwrap().0, "/queue/a");
+    assert_eq!(decoded.headers.get_message_id().unwrap().0, "message-1");
+    assert_eq!(decoded.headers.get_subscription().unwrap().0, "sub-0");
+    assert_eq!(decoded.body, &b"hello"[..]);
+}
+
+#[test]
+fn headers_are_unescaped_for_the_shared_version() {
+    let version = SharedVersion::default();
+    let mut codec = Codec::new(version.clone());
+    let bytes = b"MESSAGE\nkey:a\\cb\\nc\n\n\0";
+    let value = |codec: &mut Codec| {
+        decode_frame(codec, bytes).unwrap().headers.get_header("key").unwrap().get_value().to_owned()
+    };
+    assert_eq!(value(&mut codec), "a:b\nc");
+    version.set(StompVersion::Stomp_v1_1);
+    assert_eq!(value(&mut codec), "a:b\nc");
+    version.set(StompVersion::Stomp_v1_0);
+    assert_eq!(value(&mut codec), "a\\cb\\nc");
+}
+
+#[test]
+fn handshake_headers_are_not_unescaped() {
+    let frame = decode_frame(&mut Codec::default(), b"CONNECTED\nserver:a\\cb\n\n\0").unwrap();
+    assert_eq!(frame.headers.get_server().unwrap().0, "a\\cb");
+    let frame = decode_frame(&mut ServerCodec::default(), b"CONNECT\npasscode:a\\tb\n\n\0").unwrap();
+    assert_eq!(frame.headers.get_passcode().unwrap().0, "a\\tb");
+}
+
+#[test]
+fn undefined_escape_sequences_are_rejected() {
+    let mut buffer = BytesMut::from(&b"MESSAGE\nkey:a\\tb\n\n\0"[..]);
+    match Codec::default().decode(&mut buffer) {
+        Err(Error::Parse { message, bytes }) => {
+            assert!(message.contains("\\t"));
+            assert_eq!(bytes, b"MESSAGE\nkey:a\\tb\n\n\0");
+        },
+        _ => panic!("expected a parse error"),
+    }
+}
+
+#[test]
+fn bodies_are_split_from_the_read_buffer() {
+    let mut buffer = BytesMut::new();
+    let frame = Frame::message("/queue/a", "message-1", "sub-0", vec![7; 4096]);
+    ServerCodec::default().encode(Transmission::CompleteFrame(frame), &mut buffer).unwrap();
+    let start = buffer.as_ptr() as usize;
+    let end = start + buffer.len();
+    match Codec::default().decode(&mut buffer) {
+        Ok(Some(Transmission::CompleteFrame(frame))) => {
+            let body = frame.body.as_ptr() as usize;
+            assert!(body > start && body + frame.body.len() < end);
+            assert_eq!(frame.body, vec![7; 4096]);
+        },
+        _ => panic!("expected a frame"),
     }
 }
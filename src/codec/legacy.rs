@@ -0,0 +1,153 @@
+// The nom-based parser that `FrameDecoder` replaced. It parses the whole buffer again every time
+// more bytes arrive, and is only kept so that the streaming decoder can be checked against it.
+use bytes::BytesMut;
+use error::Error;
+use frame::{Command, Frame, Transmission};
+use header::{Header, HeaderList, StompVersion};
+use nom::{line_ending, IResult};
+use super::unescape_headers;
+
+named!(parse_server_command(&[u8]) -> Command,
+       alt!(
+           map!(tag!("CONNECTED"), |_| Command::Connected) |
+           map!(tag!("MESSAGE"), |_| Command::Message) |
+           map!(tag!("RECEIPT"), |_| Command::Receipt) |
+           map!(tag!("ERROR"), |_| Command::Error)
+       )
+);
+named!(parse_client_command(&[u8]) -> Command,
+       alt!(
+           map!(tag!("SEND"), |_| Command::Send) |
+           map!(tag!("SUBSCRIBE"), |_| Command::Subscribe) |
+           map!(tag!("UNSUBSCRIBE"), |_| Command::Unsubscribe) |
+           map!(tag!("BEGIN"), |_| Command::Begin) |
+           map!(tag!("COMMIT"), |_| Command::Commit) |
+           map!(tag!("ABORT"), |_| Command::Abort) |
+           map!(tag!("ACK"), |_| Command::Ack) |
+           map!(tag!("NACK"), |_| Command::Nack) |
+           map!(tag!("DISCONNECT"), |_| Command::Disconnect) |
+           map!(tag!("CONNECT"), |_| Command::Connect) |
+           map!(tag!("STOMP"), |_| Command::Stomp)
+       )
+);
+// Headers are parsed still escaped, and with any CR before the LF, as only STOMP 1.2 takes that
+// to be part of the line ending; `decode_transmission` sorts both out once the version is known.
+named!(parse_header(&[u8]) -> Header,
+       map!(
+           do_parse!(
+               k: is_not!(":\r\n") >>
+               tag!(":") >>
+               v: is_not!("\n") >>
+               tag!("\n") >>
+               (k, v)
+           ),
+           |(k, v): (&[u8], &[u8])| Header::new_raw(String::from_utf8_lossy(k), String::from_utf8_lossy(v))
+       )
+);
+fn get_body<'a, 'b>(bytes: &'a [u8], headers: &'b [Header]) -> ::nom::IResult<&'a [u8], &'a [u8]> {
+    let mut content_length = None;
+    for header in headers {
+        if header.0 == "content-length" {
+            trace!("found content-length header");
+            match header.1.parse::<u32>() {
+                Ok(value) => content_length = Some(value),
+                Err(error) => warn!("failed to parse content-length header: {}", error)
+            }
+        }
+    }
+    if let Some(content_length) = content_length {
+        trace!("using content-length header: {}", content_length);
+        take!(bytes, content_length)
+    }
+    else {
+        trace!("using many0 method to parse body");
+        map!(bytes,
+            many0!(is_not!("\0")),
+            |body| {
+                if body.len() == 0 {
+                    // Still within `bytes`, so that the body's position can be worked out
+                    &bytes[..0]
+                } else {
+                    body.into_iter().nth(0).unwrap()
+                }
+            }
+        )
+    }
+}
+type CommandParser = fn(&[u8]) -> IResult<&[u8], Command>;
+
+// A transmission whose body still points into the input, so that it can be split off the read
+// buffer without being copied
+enum Parsed<'a> {
+    HeartBeat,
+    Frame(Command, Vec<Header>, &'a [u8]),
+}
+
+fn parse_frame<'a>(input: &'a [u8], parse_command: CommandParser) -> IResult<&'a [u8], Parsed<'a>> {
+    map!(input,
+        do_parse!(
+            cmd: call!(parse_command) >>
+            line_ending >>
+            headers: many0!(parse_header) >>
+            line_ending >>
+            body: call!(get_body, &headers) >>
+            tag!("\0") >>
+            (cmd, headers, body)
+        ),
+        |(cmd, headers, body)| Parsed::Frame(cmd, headers, body)
+    )
+}
+fn parse_transmission<'a>(input: &'a [u8], parse_command: CommandParser) -> IResult<&'a [u8], Parsed<'a>> {
+    alt!(input,
+        map!(many1!(line_ending), |_| Parsed::HeartBeat) |
+        call!(parse_frame, parse_command)
+    )
+}
+fn decode_transmission(src: &mut BytesMut, parse_command: CommandParser, version: StompVersion)
+                       -> Result<Option<Transmission>, Error> {
+    trace!("decoding data: {:?}", src);
+    let (point, parsed) = match parse_transmission(src, parse_command) {
+        IResult::Done(rest, Parsed::HeartBeat) => (rest.len(), None),
+        IResult::Done(rest, Parsed::Frame(command, mut headers, body)) => {
+            if version >= StompVersion::Stomp_v1_2 {
+                for header in &mut headers {
+                    if header.1.ends_with('\r') {
+                        header.1.pop();
+                    }
+                }
+            }
+            let offset = body.as_ptr() as usize - src.as_ptr() as usize;
+            (rest.len(), Some((command, headers, offset..offset + body.len())))
+        },
+        IResult::Error(e) => {
+            warn!("parse error: {:?}", e);
+            return Err(Error::Parse {
+                message: format!("{}", e),
+                bytes: src.to_vec()
+            });
+        },
+        IResult::Incomplete(_) => return Ok(None)
+    };
+    let len = src.len().saturating_sub(point);
+    let bytes = src.split_to(len).freeze();
+    let mut data = match parsed {
+        None => Transmission::HeartBeat,
+        Some((command, headers, body)) => Transmission::CompleteFrame(Frame {
+            command,
+            headers: HeaderList { headers },
+            body: bytes.slice(body.start, body.end),
+        }),
+    };
+    unescape_headers(&mut data, version, &bytes)?;
+    Ok(Some(data))
+}
+
+// Decodes what a server sends, like `Codec`
+pub fn decode_from_server(src: &mut BytesMut, version: StompVersion) -> Result<Option<Transmission>, Error> {
+    decode_transmission(src, parse_server_command, version)
+}
+
+// Decodes what a client sends, like `ServerCodec`
+pub fn decode_from_client(src: &mut BytesMut, version: StompVersion) -> Result<Option<Transmission>, Error> {
+    decode_transmission(src, parse_client_command, version)
+}
@@ -1,11 +1,17 @@
 use std::cmp::max;
+use std::fmt;
+use std::time::Duration;
+use rand;
+use error::{Error, Result};
+use frame::Frame;
+use header::StompVersion;
 
 pub struct Connection {
     pub ip_address: String,
     pub port: u16,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct HeartBeat(pub u32, pub u32);
 #[derive(Clone, Copy)]
 pub struct Credentials<'a>(pub &'a str, pub &'a str);
@@ -24,6 +30,121 @@ impl OwnedCredentials {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectPolicy {
+    pub initial_delay_ms: u32,
+    pub max_delay_ms: u32,
+    pub multiplier: f32,
+    pub jitter: f32,
+    pub max_attempts: Option<u32>,
+    // Reconnect after the broker closed the connection with an ERROR as well. Off by default:
+    // whatever it refused would be replayed and refused again.
+    pub on_broker_error: bool,
+}
+
+impl ReconnectPolicy {
+    pub fn exponential(initial_delay_ms: u32, max_delay_ms: u32) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay_ms,
+            max_delay_ms,
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_attempts: None,
+            on_broker_error: false,
+        }
+    }
+
+    pub fn multiplier(mut self, multiplier: f32) -> ReconnectPolicy {
+        self.multiplier = multiplier;
+        self
+    }
+
+    // Fraction of the computed delay that may be randomly added or removed
+    pub fn jitter(mut self, jitter: f32) -> ReconnectPolicy {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn max_attempts(mut self, max_attempts: u32) -> ReconnectPolicy {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    pub fn reconnect_on_broker_error(mut self) -> ReconnectPolicy {
+        self.on_broker_error = true;
+        self
+    }
+
+    pub fn allows_attempt(&self, attempt: u32) -> bool {
+        self.max_attempts.map(|max| attempt <= max).unwrap_or(true)
+    }
+
+    // Attempts are numbered from 1
+    pub fn base_delay_ms(&self, attempt: u32) -> u32 {
+        let exponent = attempt.saturating_sub(1) as i32;
+        let delay = self.initial_delay_ms as f64 * (self.multiplier as f64).powi(exponent);
+        delay.min(self.max_delay_ms as f64) as u32
+    }
+
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.base_delay_ms(attempt) as f64;
+        let offset = base * self.jitter as f64 * (2.0 * rand::random::<f64>() - 1.0);
+        Duration::from_millis((base + offset).max(0.0) as u64)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy::exponential(500, 30_000).jitter(0.2)
+    }
+}
+
+// A broker's address
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub fn new(host: &str, port: u16) -> Endpoint {
+        Endpoint {
+            host: host.to_owned(),
+            port,
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+// Brokers to fall back on after the one given to `SessionBuilder::new`, such as the standby of a
+// primary/standby pair. Each connection attempt that fails moves on to the next broker straight
+// away; only once all of them have failed does the `ReconnectPolicy` delay apply.
+#[derive(Clone, Debug)]
+pub struct Failover {
+    pub endpoints: Vec<Endpoint>,
+    // Shuffle the whole list, including the first broker, once per session
+    pub randomize: bool,
+}
+
+impl Failover {
+    pub fn new(endpoints: Vec<Endpoint>) -> Failover {
+        Failover {
+            endpoints,
+            randomize: false,
+        }
+    }
+
+    pub fn randomize(mut self) -> Failover {
+        self.randomize = true;
+        self
+    }
+}
+
 impl Connection {
     pub fn select_heartbeat(client_tx_ms: u32,
                             client_rx_ms: u32,
@@ -44,4 +165,62 @@ impl Connection {
         }
         (heartbeat_tx_ms, heartbeat_rx_ms)
     }
+
+    // The version the broker picked in its CONNECTED frame, which must be one the client offered.
+    // A broker that leaves out the version header speaks STOMP 1.0.
+    pub fn select_version(accepted: &[StompVersion], connected_frame: &Frame) -> Result<StompVersion> {
+        let version = match connected_frame.headers.get_header("version") {
+            None => Ok(StompVersion::Stomp_v1_0),
+            Some(header) => header.get_value().parse().map_err(|_| header.get_value().to_owned()),
+        };
+        match version {
+            Ok(version) if accepted.contains(&version) => Ok(version),
+            Ok(version) => Err(version.to_string()),
+            Err(unknown) => Err(unknown),
+        }.map_err(|version| Error::Protocol {
+            message: format!("the broker chose unsupported protocol version '{}'", version),
+            frame: Some(connected_frame.clone()),
+        })
+    }
+}
+
+#[test]
+fn reconnect_delay_grows_exponentially_up_to_max() {
+    let policy = ReconnectPolicy::exponential(100, 1_000);
+    assert_eq!(policy.base_delay_ms(1), 100);
+    assert_eq!(policy.base_delay_ms(2), 200);
+    assert_eq!(policy.base_delay_ms(4), 800);
+    assert_eq!(policy.base_delay_ms(5), 1_000);
+    assert_eq!(policy.base_delay_ms(60), 1_000);
+}
+
+#[test]
+fn reconnect_jitter_stays_within_bounds() {
+    let policy = ReconnectPolicy::exponential(1_000, 1_000).jitter(0.5);
+    for _ in 0..100 {
+        let delay = policy.delay(1);
+        assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_millis(1_500));
+    }
+}
+
+#[test]
+fn reconnect_attempts_are_bounded() {
+    let policy = ReconnectPolicy::exponential(100, 1_000).max_attempts(3);
+    assert!(policy.allows_attempt(3));
+    assert!(!policy.allows_attempt(4));
+    assert!(ReconnectPolicy::default().allows_attempt(u32::MAX));
+}
+
+#[test]
+fn selected_version_must_have_been_offered() {
+    use header::Header;
+    let accepted = [StompVersion::Stomp_v1_1, StompVersion::Stomp_v1_2];
+    let connected = |version: &str| Frame::connected(version, 0, 0);
+    assert_eq!(Connection::select_version(&accepted, &connected("1.1")).unwrap(), StompVersion::Stomp_v1_1);
+    assert!(Connection::select_version(&accepted, &connected("1.0")).is_err());
+    assert!(Connection::select_version(&accepted, &connected("2.0")).is_err());
+    let mut unversioned = connected("1.2");
+    unversioned.headers.retain(|h: &Header| h.get_key() != "version");
+    assert_eq!(Connection::select_version(&[StompVersion::Stomp_v1_0], &unversioned).unwrap(),
+               StompVersion::Stomp_v1_0);
 }
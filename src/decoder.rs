@@ -0,0 +1,457 @@
+use std::mem;
+use std::ops::Range;
+use bytes::BytesMut;
+use codec::{unescape_headers, FrameLimits};
+use error::{Error, Result};
+use frame::{Command, Frame, Transmission};
+use header::{Header, HeaderList, StompVersion};
+
+// Whose commands a decoder accepts
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Peer {
+    Server,
+    Client,
+}
+
+impl Peer {
+    fn command(&self, line: &[u8]) -> Option<Command> {
+        let command = match (*self, line) {
+            (Peer::Server, b"CONNECTED") => Command::Connected,
+            (Peer::Server, b"MESSAGE") => Command::Message,
+            (Peer::Server, b"RECEIPT") => Command::Receipt,
+            (Peer::Server, b"ERROR") => Command::Error,
+            (Peer::Client, b"SEND") => Command::Send,
+            (Peer::Client, b"SUBSCRIBE") => Command::Subscribe,
+            (Peer::Client, b"UNSUBSCRIBE") => Command::Unsubscribe,
+            (Peer::Client, b"BEGIN") => Command::Begin,
+            (Peer::Client, b"COMMIT") => Command::Commit,
+            (Peer::Client, b"ABORT") => Command::Abort,
+            (Peer::Client, b"ACK") => Command::Ack,
+            (Peer::Client, b"NACK") => Command::Nack,
+            (Peer::Client, b"DISCONNECT") => Command::Disconnect,
+            (Peer::Client, b"CONNECT") => Command::Connect,
+            (Peer::Client, b"STOMP") => Command::Stomp,
+            _ => return None,
+        };
+        Some(command)
+    }
+}
+
+// Why a transmission could not be decoded
+enum Rejection {
+    Malformed(String),
+    TooLarge(String),
+}
+
+impl From<String> for Rejection {
+    fn from(message: String) -> Rejection {
+        Rejection::Malformed(message)
+    }
+}
+
+impl<'a> From<&'a str> for Rejection {
+    fn from(message: &'a str) -> Rejection {
+        Rejection::Malformed(message.to_owned())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    // Between frames, where an EOL is a heart-beat
+    Idle,
+    Headers,
+    // `end` is where the NUL must be when the frame has a content-length
+    Body { start: usize, end: Option<usize> },
+}
+
+// Decodes transmissions as their bytes arrive, remembering how far it got so that nothing is
+// examined twice. Bytes are only removed from the buffer once a whole transmission has been
+// read, so it must always be handed the same buffer.
+#[derive(Clone, Debug)]
+pub(crate) struct FrameDecoder {
+    peer: Peer,
+    pub limits: FrameLimits,
+    state: State,
+    // The start of the next line to parse
+    position: usize,
+    // Everything before this has been searched for the end of the current line or body
+    scanned: usize,
+    command: Option<Command>,
+    headers: Vec<Header>,
+}
+
+impl FrameDecoder {
+    pub fn new(peer: Peer) -> FrameDecoder {
+        FrameDecoder {
+            peer,
+            limits: FrameLimits::default(),
+            state: State::Idle,
+            position: 0,
+            scanned: 0,
+            command: None,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn decode(&mut self, src: &mut BytesMut, version: StompVersion) -> Result<Option<Transmission>> {
+        match self.advance(src, version) {
+            Ok(Some((frame_length, body))) => {
+                let raw = src.split_to(frame_length).freeze();
+                let mut transmission = Transmission::CompleteFrame(Frame {
+                    command: self.command.take().unwrap_or(Command::Error),
+                    headers: HeaderList { headers: mem::take(&mut self.headers) },
+                    body: raw.slice(body.start, body.end),
+                });
+                self.reset();
+                unescape_headers(&mut transmission, version, &raw)?;
+                Ok(Some(transmission))
+            },
+            Ok(None) if self.heart_beat(src) => Ok(Some(Transmission::HeartBeat)),
+            Ok(None) => Ok(None),
+            Err(Rejection::Malformed(message)) => {
+                warn!("parse error: {}", message);
+                self.reset();
+                Err(Error::Parse {
+                    message,
+                    bytes: src.to_vec(),
+                })
+            },
+            Err(Rejection::TooLarge(message)) => {
+                warn!("frame too large: {}", message);
+                self.reset();
+                Err(Error::FrameTooLarge(message))
+            },
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = State::Idle;
+        self.position = 0;
+        self.scanned = 0;
+        self.command = None;
+        self.headers.clear();
+    }
+
+    // Consumes an EOL found between frames
+    fn heart_beat(&mut self, src: &mut BytesMut) -> bool {
+        let length = match (self.state, &src[..]) {
+            (State::Idle, [b'\n', ..]) => 1,
+            (State::Idle, [b'\r', b'\n', ..]) => 2,
+            _ => return false,
+        };
+        src.split_to(length);
+        self.reset();
+        true
+    }
+
+    // Parses as much of the buffer as possible. Once a frame is complete, returns its length
+    // and the position of its body.
+    fn advance(&mut self, src: &BytesMut, version: StompVersion)
+               -> ::std::result::Result<Option<(usize, Range<usize>)>, Rejection> {
+        // Only STOMP 1.2 lets a CR before the LF end a header line; earlier versions have no escape
+        // for it, so there it belongs to the value
+        let crlf = version >= StompVersion::Stomp_v1_2;
+        loop {
+            match self.state {
+                State::Idle => {
+                    if src.starts_with(b"\n") || src.starts_with(b"\r\n") || &src[..] == b"\r" {
+                        return Ok(None);
+                    }
+                    let line = match self.next_line(src, true)? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    let command = self.peer.command(&src[line.clone()]).ok_or_else(|| {
+                        format!("unexpected command {:?}", String::from_utf8_lossy(&src[line]))
+                    })?;
+                    self.command = Some(command);
+                    self.state = State::Headers;
+                },
+                State::Headers => {
+                    let line = match self.next_line(src, crlf)? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    if line.start == line.end || &src[line.clone()] == b"\r" {
+                        self.state = self.body_state()?;
+                        continue;
+                    }
+                    if self.headers.len() >= self.limits.max_headers {
+                        return Err(Rejection::TooLarge(format!("more than {} headers", self.limits.max_headers)));
+                    }
+                    self.headers.push(parse_header(&src[line])?);
+                },
+                State::Body { start, end: Some(end) } => {
+                    if src.len() <= end {
+                        return Ok(None);
+                    }
+                    if src[end] != 0 {
+                        return Err("the body is longer than its content-length".into());
+                    }
+                    return Ok(Some((end + 1, start..end)));
+                },
+                State::Body { start, end: None } => {
+                    let (end, complete) = match src[self.scanned..].iter().position(|&b| b == 0) {
+                        Some(offset) => (self.scanned + offset, true),
+                        None => (src.len(), false),
+                    };
+                    if end - start > self.limits.max_body_length {
+                        return Err(Rejection::TooLarge(format!("the body is longer than {} bytes",
+                                                               self.limits.max_body_length)));
+                    }
+                    if !complete {
+                        self.scanned = src.len();
+                        return Ok(None);
+                    }
+                    return Ok(Some((end + 1, start..end)));
+                },
+            }
+        }
+    }
+
+    // The next complete line, without its LF and, if `crlf` is set, a CR before it
+    fn next_line(&mut self, src: &BytesMut, crlf: bool) -> ::std::result::Result<Option<Range<usize>>, Rejection> {
+        let start = self.position;
+        let newline = match src[self.scanned..].iter().position(|&b| b == b'\n') {
+            Some(offset) => self.scanned + offset,
+            None => {
+                self.scanned = src.len();
+                if src.len() - start > self.limits.max_line_length {
+                    return Err(Rejection::TooLarge(format!("a line is longer than {} bytes", self.limits.max_line_length)));
+                }
+                return Ok(None);
+            },
+        };
+        let end = if crlf && newline > start && src[newline - 1] == b'\r' { newline - 1 } else { newline };
+        if end - start > self.limits.max_line_length {
+            return Err(Rejection::TooLarge(format!("a line is longer than {} bytes", self.limits.max_line_length)));
+        }
+        self.position = newline + 1;
+        self.scanned = self.position;
+        Ok(Some(start..end))
+    }
+
+    fn body_state(&self) -> ::std::result::Result<State, Rejection> {
+        let start = self.position;
+        // Repeated headers are ignored, as the spec asks
+        let content_length = self.headers.iter()
+            .find(|header| header.get_key() == "content-length")
+            .and_then(|header| match header.get_value().parse::<usize>() {
+                Ok(length) => Some(length),
+                Err(e) => {
+                    warn!("failed to parse content-length header: {}", e);
+                    None
+                },
+            });
+        match content_length {
+            Some(length) if length > self.limits.max_body_length => {
+                Err(Rejection::TooLarge(format!("the content-length of {} is more than {} bytes",
+                                                length, self.limits.max_body_length)))
+            },
+            Some(length) => match start.checked_add(length) {
+                Some(end) => Ok(State::Body { start, end: Some(end) }),
+                None => Err(format!("the content-length of {} is too large", length).into()),
+            },
+            None => Ok(State::Body { start, end: None }),
+        }
+    }
+}
+
+fn parse_header(line: &[u8]) -> ::std::result::Result<Header, String> {
+    match line.iter().position(|&b| b == b':') {
+        Some(0) => Err("a header has no name".to_owned()),
+        Some(colon) => Ok(Header::new_raw(String::from_utf8_lossy(&line[..colon]),
+                                          String::from_utf8_lossy(&line[colon + 1..]))),
+        None => Err(format!("header line {:?} has no ':'", String::from_utf8_lossy(line))),
+    }
+}
+
+#[cfg(test)]
+fn decode_all(decoder: &mut FrameDecoder, src: &mut BytesMut) -> Result<Vec<Frame>> {
+    let mut frames = vec![];
+    while let Some(transmission) = decoder.decode(src, StompVersion::Stomp_v1_2)? {
+        if let Transmission::CompleteFrame(frame) = transmission {
+            frames.push(frame);
+        }
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+fn summary(frame: &Frame) -> (String, Vec<(String, String)>, Vec<u8>) {
+    let headers = frame.headers.iter().map(|h| (h.0.clone(), h.1.clone())).collect();
+    (frame.command.to_string(), headers, frame.body.to_vec())
+}
+
+#[cfg(all(test, feature = "legacy-parser"))]
+fn random_frame<R: ::rand::Rng>(rng: &mut R) -> Frame {
+    // CONNECTED is left out because its headers cannot be escaped
+    let commands = [Command::Message, Command::Receipt, Command::Error];
+    let value_chars = b"ab:\\\n\r \xff";
+    let mut headers = HeaderList::new();
+    for _ in 0..rng.gen_range(0, 5) {
+        let key: String = (0..rng.gen_range(1, 8)).map(|_| rng.gen_range(b'a', b'z') as char).collect();
+        let value: Vec<u8> = (0..rng.gen_range(1, 12))
+            .map(|_| value_chars[rng.gen_range(0, value_chars.len())])
+            .collect();
+        headers.push(Header::new(&key, &String::from_utf8_lossy(&value)));
+    }
+    let mut body: Vec<u8> = (0..rng.gen_range(0, 64)).map(|_| rng.gen::<u8>()).collect();
+    if rng.gen() {
+        headers.push(Header::new("content-length", &body.len().to_string()));
+    } else {
+        body.retain(|&b| b != 0);
+    }
+    Frame {
+        command: commands[rng.gen_range(0, commands.len())],
+        headers,
+        body: body.into(),
+    }
+}
+
+#[test]
+fn frames_can_arrive_a_byte_at_a_time() {
+    let mut src = BytesMut::new();
+    let mut frame = Frame::message("/queue/a", "message-1", "sub-0", b"with a length");
+    frame.write(&mut src);
+    src.extend(b"\r\n\n");
+    frame.headers.retain(|header| header.get_key() != "content-length");
+    frame.write(&mut src);
+
+    let mut decoder = FrameDecoder::new(Peer::Server);
+    let mut buffer = BytesMut::new();
+    let mut transmissions = vec![];
+    for byte in src.iter() {
+        buffer.extend(&[*byte]);
+        if let Some(transmission) = decoder.decode(&mut buffer, StompVersion::Stomp_v1_2).unwrap() {
+            transmissions.push(transmission);
+        }
+    }
+    assert!(buffer.is_empty());
+    assert_eq!(transmissions.len(), 4);
+    assert!(matches!(transmissions[1], Transmission::HeartBeat));
+    assert!(matches!(transmissions[2], Transmission::HeartBeat));
+    for transmission in [&transmissions[0], &transmissions[3]].iter() {
+        match **transmission {
+            Transmission::CompleteFrame(ref frame) => assert_eq!(frame.body, &b"with a length"[..]),
+            _ => panic!("expected a frame"),
+        }
+    }
+}
+
+#[cfg(feature = "legacy-parser")]
+#[test]
+fn decodes_the_same_frames_as_the_legacy_parser() {
+    use rand::Rng;
+    let mut rng = ::rand::thread_rng();
+    for _ in 0..500 {
+        let mut src = BytesMut::new();
+        for _ in 0..3 {
+            if rng.gen() {
+                src.extend(b"\n");
+            }
+            random_frame(&mut rng).write(&mut src);
+        }
+
+        let mut legacy = src.clone();
+        let mut expected = vec![];
+        while let Some(transmission) = ::codec::legacy::decode_from_server(&mut legacy, StompVersion::Stomp_v1_2).unwrap() {
+            if let Transmission::CompleteFrame(frame) = transmission {
+                expected.push(summary(&frame));
+            }
+        }
+
+        let mut decoder = FrameDecoder::new(Peer::Server);
+        let mut buffer = BytesMut::new();
+        let mut decoded = vec![];
+        while !src.is_empty() {
+            let chunk = rng.gen_range(1, 40).min(src.len());
+            buffer.extend(&src.split_to(chunk));
+            decoded.extend(decode_all(&mut decoder, &mut buffer).unwrap().iter().map(summary));
+        }
+        assert_eq!(decoded, expected);
+    }
+}
+
+#[test]
+fn carriage_returns_end_lines_only_in_stomp_1_2() {
+    let mut frame = Frame::message("/queue/a", "message-1", "sub-0", b"body");
+    frame.headers.push(Header::new("trailing", "value\r"));
+    let mut src = BytesMut::new();
+    frame.write_for(&mut src, StompVersion::Stomp_v1_1);
+    let expected = summary(&frame);
+    #[cfg(feature = "legacy-parser")]
+    match ::codec::legacy::decode_from_server(&mut src.clone(), StompVersion::Stomp_v1_1).unwrap() {
+        Some(Transmission::CompleteFrame(decoded)) => assert_eq!(summary(&decoded), expected),
+        _ => panic!("expected a frame"),
+    }
+    match FrameDecoder::new(Peer::Server).decode(&mut src, StompVersion::Stomp_v1_1).unwrap() {
+        Some(Transmission::CompleteFrame(decoded)) => assert_eq!(summary(&decoded), expected),
+        _ => panic!("expected a frame"),
+    }
+
+    let crlf = b"MESSAGE\r\nkey:value\r\n\r\nbody\0";
+    let frames = decode_all(&mut FrameDecoder::new(Peer::Server), &mut BytesMut::from(&crlf[..])).unwrap();
+    assert_eq!(frames[0].headers.get_header("key").unwrap().get_value(), "value");
+}
+
+#[test]
+fn stomp_1_0_headers_cannot_forge_others() {
+    let mut frame = Frame::message("/queue/a", "message-1", "sub-0", b"body");
+    frame.headers.push(Header::new("value", "a\nforged:value"));
+    frame.headers.push(Header::new("forged:key", "b"));
+    let mut src = BytesMut::new();
+    frame.write_for(&mut src, StompVersion::Stomp_v1_0);
+    let decoded = match FrameDecoder::new(Peer::Server).decode(&mut src, StompVersion::Stomp_v1_0).unwrap() {
+        Some(Transmission::CompleteFrame(decoded)) => decoded,
+        _ => panic!("expected a frame"),
+    };
+    assert_eq!(decoded.headers.iter().count(), frame.headers.iter().count());
+    assert!(decoded.headers.get_header("forged").is_none());
+    assert_eq!(decoded.headers.get_header("message-id").unwrap().get_value(), "message-1");
+}
+
+#[test]
+fn malformed_frames_are_rejected() {
+    let inputs: [&[u8]; 4] = [b"BOGUS\n\n\0",
+                              b"MESSAGE\nno-colon\n\n\0",
+                              b"MESSAGE\n:no-name\n\n\0",
+                              b"MESSAGE\ncontent-length:2\n\nabc\0"];
+    for input in inputs.iter() {
+        let mut src = BytesMut::from(*input);
+        match decode_all(&mut FrameDecoder::new(Peer::Server), &mut src) {
+            Err(Error::Parse { bytes, .. }) => assert_eq!(&bytes[..], *input),
+            _ => panic!("expected {:?} to be rejected", String::from_utf8_lossy(input)),
+        }
+        #[cfg(feature = "legacy-parser")]
+        assert!(::codec::legacy::decode_from_server(&mut BytesMut::from(*input), StompVersion::Stomp_v1_2).is_err());
+    }
+    // The legacy parser reads content-length as a u32, so it cannot be checked against this one
+    let huge = b"MESSAGE\ncontent-length:18446744073709551615\n\nabc";
+    match decode_all(&mut FrameDecoder::new(Peer::Server), &mut BytesMut::from(&huge[..])) {
+        Err(Error::Parse { .. }) => {},
+        _ => panic!("expected a content-length that overflows to be rejected"),
+    }
+}
+
+#[test]
+fn limits_are_enforced_on_partial_and_complete_frames() {
+    let cases: [(&[u8], FrameLimits); 5] = [
+        (b"MESSAGE\nkey:0123456789", FrameLimits { max_line_length: 8, ..FrameLimits::default() }),
+        (b"MESSAGE\na:1\nb:2\nc:3\n", FrameLimits { max_headers: 2, ..FrameLimits::default() }),
+        (b"MESSAGE\n\n0123456789", FrameLimits { max_body_length: 8, ..FrameLimits::default() }),
+        (b"MESSAGE\n\n0123456789abcdef\0", FrameLimits { max_body_length: 8, ..FrameLimits::default() }),
+        (b"MESSAGE\ncontent-length:9\n\n", FrameLimits { max_body_length: 8, ..FrameLimits::default() }),
+    ];
+    for &(input, limits) in cases.iter() {
+        let mut decoder = FrameDecoder::new(Peer::Server);
+        decoder.limits = limits;
+        match decode_all(&mut decoder, &mut BytesMut::from(input)) {
+            Err(Error::FrameTooLarge(_)) => {},
+            _ => panic!("expected {:?} to exceed {:?}", String::from_utf8_lossy(input), limits),
+        }
+    }
+    let mut decoder = FrameDecoder::new(Peer::Server);
+    decoder.limits.max_body_length = 8;
+    assert_eq!(decode_all(&mut decoder, &mut BytesMut::from(&b"MESSAGE\n\n01234567\0"[..])).unwrap().len(), 1);
+}
@@ -0,0 +1,165 @@
+use std::error;
+use std::fmt;
+use std::io;
+use bytes::Bytes;
+use frame::Frame;
+
+#[derive(Debug)]
+pub enum Error {
+    // The underlying connection failed
+    Io(io::Error),
+    // A TLS or WebSocket layer failed
+    Transport(Box<dyn error::Error + Send + Sync>),
+    // The host name did not resolve to any address
+    AddressResolution(String),
+    // A connection URI could not be understood
+    InvalidUri {
+        uri: String,
+        reason: String,
+    },
+    // The bytes received could not be parsed as a STOMP transmission
+    Parse {
+        message: String,
+        bytes: Vec<u8>,
+    },
+    // A frame exceeded one of the configured `FrameLimits`
+    FrameTooLarge(String),
+    // A header cannot be written without changing the frame's meaning
+    InvalidHeader(String),
+    // The peer sent something that is not allowed by the protocol
+    Protocol {
+        message: String,
+        frame: Option<Frame>,
+    },
+    // The broker rejected the CONNECT frame, usually because of bad credentials
+    Authentication(Frame),
+    // The broker sent an ERROR frame
+    Broker(Box<BrokerError>),
+    // No data arrived within the negotiated heart-beat interval
+    HeartbeatTimeout,
+    // The broker did not acknowledge the receipt with this id in time
+    ReceiptTimeout(String),
+    // The session was dropped before the operation could complete
+    SessionClosed,
+    // The operation needs a connection, but the session is not currently connected
+    NotConnected,
+    // Too many reliably sent messages are still awaiting confirmation
+    OutboxFull,
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+// An ERROR frame from the broker, which closes the connection after sending it
+#[derive(Clone, Debug)]
+pub struct BrokerError {
+    // The `message` header, a short description of the error
+    pub message: Option<String>,
+    // Usually a longer description
+    pub body: Bytes,
+    // Set when the ERROR is about a frame that asked for a receipt
+    pub receipt_id: Option<String>,
+    // The frame that caused the error, if it could be told from the `receipt-id`
+    pub original: Option<Frame>,
+    pub frame: Frame,
+}
+
+impl BrokerError {
+    pub fn new(frame: Frame, original: Option<Frame>) -> BrokerError {
+        BrokerError {
+            message: frame.headers.get_header("message").map(|h| h.get_value().to_owned()),
+            body: frame.body.clone(),
+            receipt_id: frame.headers.get_receipt_id().map(|r| r.0.to_owned()),
+            original,
+            frame,
+        }
+    }
+}
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message.as_deref().unwrap_or("no message"))?;
+        if let Some(ref original) = self.original {
+            write!(f, " (in reply to {})", original.command)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error {
+    pub fn protocol<S: Into<String>>(message: S) -> Error {
+        Error::Protocol {
+            message: message.into(),
+            frame: None,
+        }
+    }
+
+    pub fn transport<E>(error: E) -> Error
+        where E: Into<Box<dyn error::Error + Send + Sync>>
+    {
+        Error::Transport(error.into())
+    }
+
+    // The frame that caused this error, if any
+    pub fn frame(&self) -> Option<&Frame> {
+        match *self {
+            Error::Protocol { ref frame, .. } => frame.as_ref(),
+            Error::Authentication(ref frame) => Some(frame),
+            Error::Broker(ref error) => Some(&error.frame),
+            _ => None,
+        }
+    }
+}
+
+fn error_message(frame: &Frame) -> &str {
+    frame.headers.get_header("message").map(|h| h.get_value()).unwrap_or("no message")
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Transport(ref e) => write!(f, "transport error: {}", e),
+            Error::AddressResolution(ref host) => write!(f, "'{}' resolved to no addresses", host),
+            Error::InvalidUri { ref uri, ref reason } => write!(f, "invalid URI '{}': {}", uri, reason),
+            Error::Parse { ref message, ref bytes } => {
+                write!(f, "failed to parse {} byte(s): {}", bytes.len(), message)
+            },
+            Error::FrameTooLarge(ref message) => write!(f, "frame too large: {}", message),
+            Error::InvalidHeader(ref message) => write!(f, "invalid header: {}", message),
+            Error::Protocol { ref message, .. } => write!(f, "protocol violation: {}", message),
+            Error::Authentication(ref frame) => {
+                write!(f, "connection refused by broker: {}", error_message(frame))
+            },
+            Error::Broker(ref error) => write!(f, "broker error: {}", error),
+            Error::HeartbeatTimeout => write!(f, "heart-beat timed out"),
+            Error::ReceiptTimeout(ref id) => write!(f, "timed out waiting for receipt '{}'", id),
+            Error::SessionClosed => write!(f, "session closed"),
+            Error::NotConnected => write!(f, "not connected"),
+            Error::OutboxFull => write!(f, "the outbox is full"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::Transport(ref e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+#[test]
+fn io_errors_are_chained_as_the_source() {
+    use std::error::Error as StdError;
+    let error = Error::from(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+    assert_eq!(error.source().unwrap().to_string(), "reset");
+    assert!(Error::HeartbeatTimeout.source().is_none());
+}
@@ -1,10 +1,11 @@
 use header::HeaderList;
-use header::Header;
-use subscription::AckMode;
+use header::{self, Header, StompVersion};
+use subscription::{AckMode, AckOrNack};
+use error::{Error, Result};
 use std::str::from_utf8;
 use std::fmt;
 use std::fmt::Formatter;
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 
 #[derive(Copy, Clone, Debug)]
 pub enum Command {
@@ -47,31 +48,56 @@ impl Command {
         }
     }
 }
+impl Command {
+    // Headers of the CONNECT/CONNECTED handshake are sent as they are, so that older servers can
+    // still read them
+    pub fn escapes_headers(&self) -> bool {
+        !matches!(*self, Command::Connect | Command::Stomp | Command::Connected)
+    }
+}
 impl fmt::Display for Command {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(f, "{}", self.as_str())
     }
 }
+// Anything that can become a frame body. Owned buffers are handed over without copying.
 pub trait ToFrameBody {
-    fn to_frame_body<'a>(&'a self) -> &'a [u8];
+    fn to_frame_body(self) -> Bytes;
 }
 
 impl<'b> ToFrameBody for &'b [u8] {
-    fn to_frame_body<'a>(&'a self) -> &'a [u8] {
-        self
+    fn to_frame_body(self) -> Bytes {
+        Bytes::from(self)
+    }
+}
+
+impl<const N: usize> ToFrameBody for &[u8; N] {
+    fn to_frame_body(self) -> Bytes {
+        Bytes::from(&self[..])
     }
 }
 
 impl<'b> ToFrameBody for &'b str {
-    fn to_frame_body<'a>(&'a self) -> &'a [u8] {
-        self.as_bytes()
+    fn to_frame_body(self) -> Bytes {
+        Bytes::from(self)
     }
 }
 
 impl ToFrameBody for String {
-    fn to_frame_body<'a>(&'a self) -> &'a [u8] {
-        let string: &str = self.as_ref();
-        string.as_bytes()
+    fn to_frame_body(self) -> Bytes {
+        Bytes::from(self)
+    }
+}
+
+impl ToFrameBody for Vec<u8> {
+    fn to_frame_body(self) -> Bytes {
+        Bytes::from(self)
+    }
+}
+
+impl ToFrameBody for Bytes {
+    fn to_frame_body(self) -> Bytes {
+        self
     }
 }
 
@@ -79,7 +105,7 @@ impl ToFrameBody for String {
 pub struct Frame {
     pub command: Command,
     pub headers: HeaderList,
-    pub body: Vec<u8>,
+    pub body: Bytes,
 }
 
 #[derive(Debug)]
@@ -90,9 +116,13 @@ pub enum Transmission {
 
 impl Transmission {
     pub fn write(&self, out: &mut BytesMut) {
+        self.write_for(out, StompVersion::Stomp_v1_2)
+    }
+
+    pub fn write_for(&self, out: &mut BytesMut, version: StompVersion) {
         match *self {
             Transmission::HeartBeat => out.extend("\n".as_bytes()),
-            Transmission::CompleteFrame(ref frame) => frame.write(out),
+            Transmission::CompleteFrame(ref frame) => frame.write_for(out, version),
         }
     }
 }
@@ -135,12 +165,19 @@ impl Frame {
     }
 
     pub fn write(&self, out: &mut BytesMut) {
+        self.write_for(out, StompVersion::Stomp_v1_2)
+    }
+
+    // Writes the frame with its headers escaped the way `version` requires
+    pub fn write_for(&self, out: &mut BytesMut, version: StompVersion) {
+        // STOMP 1.0 is the version without any escaping
+        let version = if self.command.escapes_headers() { version } else { StompVersion::Stomp_v1_0 };
         debug!("Sending frame:\n{}", self.to_str());
         out.extend(self.command.as_str().as_bytes());
         out.extend("\n".as_bytes());
 
         for header in self.headers.iter() {
-            out.extend(header.get_raw().as_bytes());
+            out.extend(header.get_raw_for(version).as_bytes());
             out.extend("\n".as_bytes());
         }
 
@@ -151,6 +188,27 @@ impl Frame {
         debug!("write() complete.");
     }
 
+    // The headers of CONNECT, STOMP and CONNECTED frames are not escaped, so a line break in one
+    // would be read as the start of another. Values are left out of the error, as they may be
+    // credentials.
+    pub fn check_headers(&self) -> Result<()> {
+        if self.command.escapes_headers() {
+            return Ok(());
+        }
+        for header in self.headers.iter() {
+            let key = header.get_key();
+            if key.contains(['\r', '\n', ':']) {
+                return Err(Error::InvalidHeader(format!("{} header name {:?} contains a line break or ':'",
+                                                        self.command, key)));
+            }
+            if header.get_value().contains(['\r', '\n']) {
+                return Err(Error::InvalidHeader(format!("{} header '{}' contains a line break",
+                                                        self.command, key)));
+            }
+        }
+        Ok(())
+    }
+
     pub fn connect(tx_heartbeat_ms: u32, rx_heartbeat_ms: u32) -> Frame {
         let heart_beat = format!("{},{}", tx_heartbeat_ms, rx_heartbeat_ms);
         let connect_frame = Frame {
@@ -160,18 +218,30 @@ impl Frame {
                 "heart-beat" => heart_beat.as_ref(),
                 "content-length" => "0"
             ],
-            body: Vec::new(),
+            body: Bytes::new(),
         };
         connect_frame
     }
 
+    pub fn connected(version: &str, tx_heartbeat_ms: u32, rx_heartbeat_ms: u32) -> Frame {
+        let heart_beat = format!("{},{}", tx_heartbeat_ms, rx_heartbeat_ms);
+        let connected_frame = Frame {
+            command: Command::Connected,
+            headers: header_list![
+                "version" => version,
+                "heart-beat" => heart_beat.as_ref()
+            ],
+            body: Bytes::new(),
+        };
+        connected_frame
+    }
+
+    // Sessions add a receipt header so that they know when the broker has let go
     pub fn disconnect() -> Frame {
         let disconnect_frame = Frame {
             command: Command::Disconnect,
-            headers: header_list![
-                "receipt" => "msg/disconnect"
-            ],
-            body: Vec::new(),
+            headers: HeaderList::new(),
+            body: Bytes::new(),
         };
         disconnect_frame
     }
@@ -185,7 +255,7 @@ impl Frame {
                 "id" => subscription_id,
                 "ack" => ack_mode.as_text()
             ],
-            body: Vec::new(),
+            body: Bytes::new(),
         };
         subscribe_frame
     }
@@ -196,7 +266,7 @@ impl Frame {
             headers: header_list![
                 "id" => subscription_id
             ],
-            body: Vec::new(),
+            body: Bytes::new(),
         };
         unsubscribe_frame
     }
@@ -207,7 +277,7 @@ impl Frame {
             headers: header_list![
                 "id" => ack_id
             ],
-            body: Vec::new(),
+            body: Bytes::new(),
         };
         ack_frame
     }
@@ -218,30 +288,121 @@ impl Frame {
             headers: header_list![
                 "id" => message_id
             ],
-            body: Vec::new(),
+            body: Bytes::new(),
         };
         nack_frame
     }
 
-    pub fn send(destination: &str, body: &[u8]) -> Frame {
+    // The ACK or NACK for a MESSAGE frame, identifying it the way the negotiated version requires:
+    // by its `ack` header in 1.2, and by `message-id` and `subscription` in 1.0 and 1.1.
+    pub fn acknowledgement(message: &Frame,
+                           which: AckOrNack,
+                           version: StompVersion,
+                           transaction_id: Option<&str>)
+                           -> Result<Frame> {
+        let missing = |header: &str| Error::Protocol {
+            message: format!("cannot acknowledge a frame without a '{}' header", header),
+            frame: Some(message.clone()),
+        };
+        let mut acknowledgement = match version {
+            StompVersion::Stomp_v1_2 => {
+                let header::Ack(ack_id) = message.headers.get_ack().ok_or_else(|| missing("ack"))?;
+                match which {
+                    AckOrNack::Ack => Frame::ack(ack_id),
+                    AckOrNack::Nack => Frame::nack(ack_id),
+                }
+            },
+            StompVersion::Stomp_v1_1 | StompVersion::Stomp_v1_0 => {
+                let header::MessageId(message_id) = message.headers.get_message_id()
+                    .ok_or_else(|| missing("message-id"))?;
+                let command = match which {
+                    AckOrNack::Ack => Command::Ack,
+                    AckOrNack::Nack if version == StompVersion::Stomp_v1_0 => {
+                        return Err(Error::protocol("NACK is not part of STOMP 1.0"));
+                    },
+                    AckOrNack::Nack => Command::Nack,
+                };
+                let mut headers = header_list![
+                    "message-id" => message_id
+                ];
+                // 1.0 has no `subscription` header on ACK
+                if version == StompVersion::Stomp_v1_1 {
+                    let header::Subscription(sub_id) = message.headers.get_subscription()
+                        .ok_or_else(|| missing("subscription"))?;
+                    headers.push(Header::new("subscription", sub_id));
+                }
+                Frame {
+                    command,
+                    headers,
+                    body: Bytes::new(),
+                }
+            },
+        };
+        if let Some(transaction_id) = transaction_id {
+            acknowledgement.headers.push(Header::new("transaction", transaction_id));
+        }
+        Ok(acknowledgement)
+    }
+
+    pub fn send<T: ToFrameBody>(destination: &str, body: T) -> Frame {
+        let body = body.to_frame_body();
         let send_frame = Frame {
             command: Command::Send,
             headers: header_list![
                 "destination" => destination,
                 "content-length" => body.len().to_string().as_ref()
             ],
-            body: body.into(),
+            body,
         };
         send_frame
     }
 
+    pub fn message<T: ToFrameBody>(destination: &str, message_id: &str, subscription_id: &str, body: T) -> Frame {
+        let body = body.to_frame_body();
+        let message_frame = Frame {
+            command: Command::Message,
+            headers: header_list![
+                "destination" => destination,
+                "message-id" => message_id,
+                "subscription" => subscription_id,
+                "content-length" => body.len().to_string().as_ref()
+            ],
+            body,
+        };
+        message_frame
+    }
+
+    pub fn receipt(receipt_id: &str) -> Frame {
+        let receipt_frame = Frame {
+            command: Command::Receipt,
+            headers: header_list![
+                "receipt-id" => receipt_id
+            ],
+            body: Bytes::new(),
+        };
+        receipt_frame
+    }
+
+    pub fn error<T: ToFrameBody>(message: &str, body: T) -> Frame {
+        let body = body.to_frame_body();
+        let error_frame = Frame {
+            command: Command::Error,
+            headers: header_list![
+                "message" => message,
+                "content-length" => body.len().to_string().as_ref()
+            ],
+            body,
+        };
+        error_frame
+    }
+
     pub fn begin(transaction_id: &str) -> Frame {
         let begin_frame = Frame {
             command: Command::Begin,
             headers: header_list![
                 "transaction" => transaction_id
             ],
-            body: Vec::new(),
+            body: Bytes::new(),
         };
         begin_frame
     }
@@ -252,7 +413,7 @@ impl Frame {
             headers: header_list![
                 "transaction" => transaction_id
             ],
-            body: Vec::new(),
+            body: Bytes::new(),
         };
         abort_frame
     }
@@ -263,8 +424,49 @@ impl Frame {
             headers: header_list![
                 "transaction" => transaction_id
             ],
-            body: Vec::new(),
+            body: Bytes::new(),
         };
         commit_frame
     }
 }
+
+#[test]
+fn acknowledgements_follow_the_negotiated_version() {
+    let mut message = Frame::message("/queue/a", "m-1", "sub-1", b"");
+    message.headers.push(Header::new("ack", "a-1"));
+    let v12 = Frame::acknowledgement(&message, AckOrNack::Nack, StompVersion::Stomp_v1_2, Some("tx/1")).unwrap();
+    assert!(matches!(v12.command, Command::Nack));
+    assert_eq!(v12.headers.get_header("id").unwrap().get_value(), "a-1");
+    assert_eq!(v12.headers.get_transaction().unwrap().0, "tx/1");
+
+    let v11 = Frame::acknowledgement(&message, AckOrNack::Ack, StompVersion::Stomp_v1_1, None).unwrap();
+    assert_eq!(v11.headers.get_message_id().unwrap().0, "m-1");
+    assert_eq!(v11.headers.get_subscription().unwrap().0, "sub-1");
+    assert!(v11.headers.get_header("id").is_none());
+
+    assert!(Frame::acknowledgement(&message, AckOrNack::Nack, StompVersion::Stomp_v1_0, None).is_err());
+}
+
+#[test]
+fn acknowledgements_require_the_identifying_headers() {
+    let message = Frame::message("/queue/a", "m-1", "sub-1", b"");
+    match Frame::acknowledgement(&message, AckOrNack::Ack, StompVersion::Stomp_v1_2, None) {
+        Err(Error::Protocol { frame: Some(frame), .. }) => assert_eq!(frame.headers.get_message_id().unwrap().0, "m-1"),
+        _ => panic!("expected a missing 'ack' header to be reported"),
+    }
+}
+
+#[test]
+fn connect_headers_are_written_unescaped() {
+    let mut frame = Frame::connect(0, 0);
+    frame.headers.push(Header::new("passcode", "a:b"));
+    let mut out = BytesMut::new();
+    frame.write(&mut out);
+    assert!(out.windows(11).any(|w| w == b"passcode:a:"));
+
+    let mut frame = Frame::send("/queue/a", b"");
+    frame.headers.push(Header::new("note", "a:b"));
+    let mut out = BytesMut::new();
+    frame.write(&mut out);
+    assert!(out.windows(10).any(|w| w == b"note:a\\cb\n"));
+}
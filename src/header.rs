@@ -1,8 +1,9 @@
 // Non-camel case types are used for Stomp Protocol version enum variants
 #![macro_use]
 #![allow(non_camel_case_types)]
+use std::fmt;
 use std::slice::Iter;
-use unicode_segmentation::UnicodeSegmentation;
+use std::str::FromStr;
 
 // Ideally this would be a simple typedef. However:
 // See Rust bug #11047: https://github.com/mozilla/rust/issues/11047
@@ -59,33 +60,86 @@ pub struct ContentType<'a>(pub &'a str);
 #[derive(Clone, Debug)]
 pub struct Header(pub String, pub String);
 
+// Header keys and values are kept unescaped; escaping is applied when a frame is written,
+// following the rules of the negotiated protocol version
 impl Header {
     pub fn new(key: &str, value: &str) -> Header {
-        Header(Self::encode_value(key), Self::encode_value(value))
+        Header(key.to_owned(), value.to_owned())
     }
 
     pub fn new_raw<T: Into<String>, U: Into<String>>(key: T, value: U) -> Header {
         Header(key.into(), value.into())
     }
 
+    // The header as it appears on the wire, escaped for STOMP 1.2
     pub fn get_raw(&self) -> String {
-        format!("{}:{}", self.0, self.1)
+        self.get_raw_for(StompVersion::Stomp_v1_2)
+    }
+
+    pub fn get_raw_for(&self, version: StompVersion) -> String {
+        // A colon would end a STOMP 1.0 key early
+        let key_version = if version == StompVersion::Stomp_v1_0 && self.0.contains(':') {
+            StompVersion::Stomp_v1_2
+        } else {
+            version
+        };
+        format!("{}:{}",
+                Self::encode_value_for(&self.0, key_version),
+                Self::encode_value_for(&self.1, version))
     }
 
     pub fn encode_value(value: &str) -> String {
-        let mut encoded = String::new();//self.strings.detached();
-        for grapheme in UnicodeSegmentation::graphemes(value, true) {
-            match grapheme {
-                "\\" => encoded.push_str(r"\\"),// Order is significant
-                "\r" => encoded.push_str(r"\r"),
-                "\n" => encoded.push_str(r"\n"),
-                ":" => encoded.push_str(r"\c"),
-                g => encoded.push_str(g),
+        Self::encode_value_for(value, StompVersion::Stomp_v1_2)
+    }
+
+    // STOMP 1.0 has no escaping at all and 1.1 has no escape for carriage returns. A 1.0 value
+    // with a line break in it would forge headers if written as it is, so it is escaped as 1.2
+    // would escape it instead.
+    pub fn encode_value_for(value: &str, version: StompVersion) -> String {
+        if version == StompVersion::Stomp_v1_0 {
+            if !value.contains(['\r', '\n']) {
+                return value.to_owned();
+            }
+            return Self::encode_value_for(value, StompVersion::Stomp_v1_2);
+        }
+        let mut encoded = String::with_capacity(value.len());
+        for c in value.chars() {
+            match c {
+                '\\' => encoded.push_str(r"\\"),
+                '\r' if version >= StompVersion::Stomp_v1_2 => encoded.push_str(r"\r"),
+                '\n' => encoded.push_str(r"\n"),
+                ':' => encoded.push_str(r"\c"),
+                c => encoded.push(c),
             }
         }
         encoded
     }
 
+    // Reverses `encode_value_for`. Escape sequences the version does not define are an error,
+    // reported with the offending sequence.
+    pub fn decode_value_for(value: &str, version: StompVersion) -> Result<String, String> {
+        if version == StompVersion::Stomp_v1_0 || !value.contains('\\') {
+            return Ok(value.to_owned());
+        }
+        let mut decoded = String::with_capacity(value.len());
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                decoded.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('\\') => decoded.push('\\'),
+                Some('n') => decoded.push('\n'),
+                Some('c') => decoded.push(':'),
+                Some('r') if version >= StompVersion::Stomp_v1_2 => decoded.push('\r'),
+                Some(other) => return Err(format!("\\{}", other)),
+                None => return Err("\\".to_owned()),
+            }
+        }
+        Ok(decoded)
+    }
+
     pub fn get_key<'a>(&'a self) -> &'a str {
         &self.0
     }
@@ -119,13 +173,49 @@ pub struct Transaction<'a>(pub &'a str);
 #[derive(Clone, Copy)]
 pub struct Version(pub StompVersion);
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum StompVersion {
     Stomp_v1_0,
     Stomp_v1_1,
     Stomp_v1_2,
 }
 
+impl StompVersion {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            StompVersion::Stomp_v1_0 => "1.0",
+            StompVersion::Stomp_v1_1 => "1.1",
+            StompVersion::Stomp_v1_2 => "1.2",
+        }
+    }
+}
+
+impl fmt::Display for StompVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for StompVersion {
+    type Err = ();
+    fn from_str(version: &str) -> Result<StompVersion, ()> {
+        match version.trim() {
+            "1.0" => Ok(StompVersion::Stomp_v1_0),
+            "1.1" => Ok(StompVersion::Stomp_v1_1),
+            "1.2" => Ok(StompVersion::Stomp_v1_2),
+            _ => Err(()),
+        }
+    }
+}
+
+impl AcceptVersion {
+    // The value of an `accept-version` header offering these versions
+    pub fn to_header_value(&self) -> String {
+        let versions: Vec<&str> = self.0.iter().map(StompVersion::as_str).collect();
+        versions.join(",")
+    }
+}
+
 impl HeaderList {
     pub fn get_header<'a>(&'a self, key: &str) -> Option<&'a Header> {
         self.headers.iter().find(|header| {
@@ -142,14 +232,7 @@ impl HeaderList {
             None => return None,
         };
         let versions: Vec<StompVersion> = versions.split(',')
-                                                  .filter_map(|v| {
-                                                      match v.trim() {
-                                                          "1.0" => Some(StompVersion::Stomp_v1_0),
-                                                          "1.1" => Some(StompVersion::Stomp_v1_1),
-                                                          "1.2" => Some(StompVersion::Stomp_v1_2),
-                                                          _ => None,
-                                                      }
-                                                  })
+                                                  .filter_map(|v| v.parse().ok())
                                                   .collect();
         Some(versions)
     }
@@ -265,12 +348,7 @@ impl HeaderList {
             Some(h) => h.get_value(),
             None => return None,
         };
-        match (version).as_ref() {
-            "1.0" => Some(Version(StompVersion::Stomp_v1_0)), // TODO: Impl FromStr for StompVersion
-            "1.1" => Some(Version(StompVersion::Stomp_v1_1)),
-            "1.2" => Some(Version(StompVersion::Stomp_v1_2)),
-            _ => None,
-        }
+        version.parse().ok().map(Version)
     }
 
     pub fn get_content_length(&self) -> Option<ContentLength> {
@@ -327,3 +405,23 @@ fn encode_slash() {
     let encoded = r"Hello\\World";
     assert!(encoded == Header::encode_value(unencoded));
 }
+
+#[test]
+fn escaping_follows_the_protocol_version() {
+    let unencoded = "a:b\\c\r\nd";
+    assert_eq!(Header::encode_value_for("a:b\\c", StompVersion::Stomp_v1_0), "a:b\\c");
+    assert_eq!(Header::encode_value_for(unencoded, StompVersion::Stomp_v1_1), "a\\cb\\\\c\r\\nd");
+    assert_eq!(Header::encode_value_for(unencoded, StompVersion::Stomp_v1_2), "a\\cb\\\\c\\r\\nd");
+    for version in &[StompVersion::Stomp_v1_1, StompVersion::Stomp_v1_2] {
+        let encoded = Header::encode_value_for(unencoded, *version);
+        assert_eq!(Header::decode_value_for(&encoded, *version).unwrap(), unencoded);
+    }
+}
+
+#[test]
+fn undefined_escapes_are_rejected() {
+    assert_eq!(Header::decode_value_for(r"a\tb", StompVersion::Stomp_v1_2), Err(r"\t".to_owned()));
+    assert_eq!(Header::decode_value_for(r"a\rb", StompVersion::Stomp_v1_1), Err(r"\r".to_owned()));
+    assert_eq!(Header::decode_value_for("a\\", StompVersion::Stomp_v1_2), Err("\\".to_owned()));
+    assert_eq!(Header::decode_value_for(r"a\tb", StompVersion::Stomp_v1_0).unwrap(), r"a\tb");
+}
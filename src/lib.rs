@@ -6,19 +6,46 @@ extern crate log;
 extern crate futures;
 extern crate tokio_io;
 extern crate tokio_core;
-extern crate unicode_segmentation;
 extern crate bytes;
+extern crate rand;
+#[cfg(feature = "tls")]
+extern crate native_tls;
+#[cfg(feature = "tls")]
+extern crate tokio_tls;
+#[cfg(feature = "websocket")]
+extern crate tungstenite;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "async")]
+extern crate futures_core;
+#[cfg(feature = "async")]
+extern crate futures_util;
+#[cfg(feature = "legacy-parser")]
 #[macro_use]
 extern crate nom;
 
 pub mod connection;
+pub mod error;
 pub mod header;
 pub mod codec;
+mod decoder;
 pub mod frame;
 pub mod session;
+pub mod session_handle;
 pub mod subscription;
 pub mod transaction;
 pub mod message_builder;
 pub mod session_builder;
+mod uri;
 pub mod subscription_builder;
 pub mod option_setter;
+pub use error::{BrokerError, Error, Result};
+pub mod transport;
+#[cfg(feature = "broker")]
+pub mod broker;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+#[cfg(feature = "async")]
+pub mod async_session;
@@ -1,5 +1,6 @@
-use session::{Session, ReceiptRequest, OutstandingReceipt};
+use session::{Session, ReceiptRequest, ReceiptFuture, OutstandingReceipt, GenerateReceipt};
 use frame::Frame;
+use error::Result;
 use option_setter::OptionSetter;
 
 pub struct MessageBuilder<'a> {
@@ -19,9 +20,9 @@ impl<'a> MessageBuilder<'a> {
 
     #[allow(dead_code)]
     pub fn send(self) {
-        if self.receipt_request.is_some() {
+        if self.receipt_request.is_some() && !self.session.shutting_down() {
             let request = self.receipt_request.unwrap();
-            self.session.state.outstanding_receipts.insert(
+            self.session.track_receipt(
                 request.id,
                 OutstandingReceipt::new(
                     self.frame.clone()
@@ -31,6 +32,24 @@ impl<'a> MessageBuilder<'a> {
         self.session.send_frame(self.frame)
     }
 
+    pub fn send_with_receipt(self) -> ReceiptFuture {
+        let mut builder = if self.receipt_request.is_some() { self } else { self.with(GenerateReceipt) };
+        let request = builder.receipt_request.take().unwrap();
+        let future = builder.session.receipt_future(request.id, builder.frame.clone());
+        builder.session.send_frame(builder.frame);
+        future
+    }
+
+    // Sends the message at least once: it stays in the session's outbox, and is re-sent after
+    // every reconnection, until the broker confirms it. Fails with `Error::OutboxFull` when the
+    // outbox is at capacity; see `Session::poll_outbox`. Returns the receipt id.
+    pub fn send_reliably(self) -> Result<String> {
+        let mut builder = if self.receipt_request.is_some() { self } else { self.with(GenerateReceipt) };
+        let request = builder.receipt_request.take().unwrap();
+        builder.session.publish(request.id.clone(), builder.frame)?;
+        Ok(request.id)
+    }
+
     #[allow(dead_code)]
     pub fn with<T>(self, option_setter: T) -> MessageBuilder<'a>
         where T: OptionSetter<MessageBuilder<'a>>
@@ -1,10 +1,15 @@
 use message_builder::MessageBuilder;
 use session_builder::SessionBuilder;
 use subscription_builder::SubscriptionBuilder;
-use header::{Header, SuppressedHeader, ContentType};
-use connection::{HeartBeat, Credentials, OwnedCredentials};
+use header::{AcceptVersion, Header, SuppressedHeader, ContentType};
+use connection::{HeartBeat, Credentials, Failover, OwnedCredentials, ReconnectPolicy};
 use subscription::AckMode;
-use session::{ReceiptRequest, GenerateReceipt};
+use codec::{FrameLimits, MaxLineLength, MaxHeaders, MaxBodyLength};
+use session::{ReceiptRequest, ReceiptTimeout, PendingReceiptPolicy, OutboxCapacity, SendBufferCapacity, GenerateReceipt};
+#[cfg(feature = "tls")]
+use tls::TlsConfig;
+#[cfg(feature = "async")]
+use async_session;
 
 pub trait OptionSetter<T> {
     fn set_option(self, T) -> T;
@@ -40,6 +45,15 @@ impl OptionSetter<SessionBuilder> for Header {
     }
 }
 
+// Replaces the versions offered in the CONNECT frame
+impl OptionSetter<SessionBuilder> for AcceptVersion {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.headers.retain(|header| header.get_key() != "accept-version");
+        builder.config.headers.push(Header::new("accept-version", &self.to_header_value()));
+        builder
+    }
+}
+
 impl OptionSetter<SessionBuilder> for HeartBeat {
     fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
         builder.config.heartbeat = self;
@@ -54,6 +68,84 @@ impl<'b> OptionSetter<SessionBuilder> for Credentials<'b> {
     }
 }
 
+impl OptionSetter<SessionBuilder> for ReconnectPolicy {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.reconnect = Some(self);
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for Failover {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.failover = Some(self);
+        builder
+    }
+}
+
+#[cfg(feature = "tls")]
+impl OptionSetter<SessionBuilder> for TlsConfig {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.tls = Some(self);
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for ReceiptTimeout {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.receipt_timeout = Some(self.0);
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for PendingReceiptPolicy {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.pending_receipts = self;
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for OutboxCapacity {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.outbox_capacity = self.0;
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for SendBufferCapacity {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.send_buffer_capacity = self.0;
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for FrameLimits {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.limits = self;
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for MaxLineLength {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.limits.max_line_length = self.0;
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for MaxHeaders {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.limits.max_headers = self.0;
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for MaxBodyLength {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.limits.max_body_length = self.0;
+        builder
+    }
+}
+
 impl<'b> OptionSetter<SessionBuilder> for SuppressedHeader<'b> {
     fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
         let SuppressedHeader(key) = self;
@@ -103,3 +195,54 @@ impl <'a> OptionSetter<SubscriptionBuilder<'a>> for GenerateReceipt {
         builder
     }
 }
+
+#[cfg(feature = "async")]
+impl OptionSetter<async_session::MessageBuilder> for Header {
+    fn set_option(self, mut builder: async_session::MessageBuilder) -> async_session::MessageBuilder {
+        builder.frame.headers.push(self);
+        builder
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a> OptionSetter<async_session::MessageBuilder> for SuppressedHeader<'a> {
+    fn set_option(self, mut builder: async_session::MessageBuilder) -> async_session::MessageBuilder {
+        let SuppressedHeader(key) = self;
+        builder.frame.headers.retain(|header| (*header).get_key() != key);
+        builder
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a> OptionSetter<async_session::MessageBuilder> for ContentType<'a> {
+    fn set_option(self, mut builder: async_session::MessageBuilder) -> async_session::MessageBuilder {
+        let ContentType(content_type) = self;
+        builder.frame.headers.push(Header::new("content-type", content_type));
+        builder
+    }
+}
+
+#[cfg(feature = "async")]
+impl OptionSetter<async_session::SubscriptionBuilder> for Header {
+    fn set_option(self, mut builder: async_session::SubscriptionBuilder) -> async_session::SubscriptionBuilder {
+        builder.headers.push(self);
+        builder
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a> OptionSetter<async_session::SubscriptionBuilder> for SuppressedHeader<'a> {
+    fn set_option(self, mut builder: async_session::SubscriptionBuilder) -> async_session::SubscriptionBuilder {
+        let SuppressedHeader(key) = self;
+        builder.headers.retain(|header| (*header).get_key() != key);
+        builder
+    }
+}
+
+#[cfg(feature = "async")]
+impl OptionSetter<async_session::SubscriptionBuilder> for AckMode {
+    fn set_option(self, mut builder: async_session::SubscriptionBuilder) -> async_session::SubscriptionBuilder {
+        builder.ack_mode = self;
+        builder
+    }
+}
@@ -1,33 +1,127 @@
 use std::collections::hash_map::HashMap;
-use std::io::Result;
-use connection::{self, Connection};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use error::{BrokerError, Error, Result};
+use connection::{self, Connection, Endpoint};
 use subscription::{AckMode, AckOrNack, Subscription};
 use frame::{Frame, Command, ToFrameBody};
 use frame::Transmission::{self, HeartBeat, CompleteFrame};
-use header::{self, Header};
+use header::{self, Header, StompVersion};
 use transaction::Transaction;
 use session_builder::SessionConfig;
 use message_builder::MessageBuilder;
 use subscription_builder::SubscriptionBuilder;
-use tokio_core::net::{TcpStreamNew, TcpStream};
 use tokio_core::reactor::{Timeout, Handle};
-use tokio_io::codec::Framed;
-use codec::Codec;
-use tokio_io::AsyncRead;
+use transport::{self, BoxedFrameTransport, TransportFuture};
+use codec::SharedVersion;
 use futures::*;
+use futures::sync::{mpsc, oneshot};
+use session_handle::{HandleCommand, SessionHandle};
+use std::time::Duration;
 
 const GRACE_PERIOD_MULTIPLIER: f32 = 2.0;
 
 pub struct OutstandingReceipt {
     pub original_frame: Frame,
+    notify: Option<oneshot::Sender<Result<Frame>>>,
+    expiry: Option<Timeout>,
+    sequence: u32,
+    // Sent with `send_reliably`; kept until confirmed, whatever happens to the connection
+    reliable: bool,
+    transmissions: u32,
 }
 
 impl OutstandingReceipt {
     pub fn new(original_frame: Frame) -> Self {
         OutstandingReceipt {
-            original_frame: original_frame
+            original_frame,
+            notify: None,
+            expiry: None,
+            sequence: 0,
+            reliable: false,
+            transmissions: 0,
         }
     }
+    fn resolve(&mut self, result: Result<Frame>) {
+        if let Some(notify) = self.notify.take() {
+            let _ = notify.send(result);
+        }
+    }
+}
+// Resolves to the RECEIPT frame once the broker has processed the frame that requested it.
+// The session must keep being polled for the receipt (or its timeout) to be noticed.
+pub struct ReceiptFuture {
+    id: String,
+    receiver: oneshot::Receiver<Result<Frame>>,
+}
+
+impl ReceiptFuture {
+    pub(crate) fn new(id: String, receiver: oneshot::Receiver<Result<Frame>>) -> ReceiptFuture {
+        ReceiptFuture { id, receiver }
+    }
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Future for ReceiptFuture {
+    type Item = Frame;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Frame, Error> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(Ok(receipt))) => Ok(Async::Ready(receipt)),
+            Ok(Async::Ready(Err(e))) => Err(e),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(Error::SessionClosed),
+        }
+    }
+}
+// Resolves once `Session::shutdown` has closed the connection. Fails with
+// `Error::ReceiptTimeout` if the broker did not confirm everything in time, in which case the
+// connection is closed regardless.
+pub struct Shutdown {
+    receiver: oneshot::Receiver<Result<()>>,
+}
+
+impl Future for Shutdown {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(Error::SessionClosed),
+        }
+    }
+}
+struct ShutdownState {
+    notify: oneshot::Sender<Result<()>>,
+    deadline: Timeout,
+    // Set once the DISCONNECT frame has been sent
+    receipt_id: Option<String>,
+}
+pub struct ReceiptTimeout(pub Duration);
+// The number of reliably sent messages that may await confirmation at any one time
+pub struct OutboxCapacity(pub usize);
+// The number of outbound bytes the session buffers while the connection cannot keep up,
+// beyond which `poll_send_ready` reports it as saturated
+pub struct SendBufferCapacity(pub usize);
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PublisherStats {
+    pub in_flight: usize,
+    pub confirmed: u64,
+    pub retried: u64,
+}
+// What happens to receipts that are still outstanding when the connection drops
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PendingReceiptPolicy {
+    // Forget them, failing any receipt futures with `Error::SessionClosed`
+    Fail,
+    // Re-send their frames once a reconnection attempt succeeds
+    Resend,
 }
 pub struct GenerateReceipt;
 pub struct ReceiptRequest {
@@ -43,43 +137,87 @@ impl ReceiptRequest {
 }
 
 pub struct SessionState {
+    pub connected: bool,
+    pub version: Option<StompVersion>,
     next_transaction_id: u32,
     next_subscription_id: u32,
     next_receipt_id: u32,
+    next_receipt_sequence: u32,
     pub rx_heartbeat_ms: Option<u32>,
     pub tx_heartbeat_ms: Option<u32>,
     pub rx_heartbeat_timeout: Option<Timeout>,
     pub tx_heartbeat_timeout: Option<Timeout>,
+    pub reconnect_attempts: u32,
+    pub reconnect_timeout: Option<Timeout>,
+    pub publisher_stats: PublisherStats,
+    outbox_task: Option<task::Task>,
+    send_task: Option<task::Task>,
+    send_buffer_full: bool,
+    shutdown: Option<ShutdownState>,
+    // Indexes the session's endpoints
+    endpoint: usize,
+    // The endpoints left to try before the `ReconnectPolicy` delay applies
+    untried_endpoints: usize,
     pub subscriptions: HashMap<String, Subscription>,
+    pub(crate) subscription_streams: HashMap<String, mpsc::UnboundedSender<Frame>>,
     pub outstanding_receipts: HashMap<String, OutstandingReceipt>
 }
 
 impl SessionState {
     pub fn new() -> SessionState {
         SessionState {
+            connected: false,
+            version: None,
             next_transaction_id: 0,
             next_subscription_id: 0,
             next_receipt_id: 0,
+            next_receipt_sequence: 0,
             rx_heartbeat_ms: None,
             rx_heartbeat_timeout: None,
             tx_heartbeat_ms: None,
             tx_heartbeat_timeout: None,
+            reconnect_attempts: 0,
+            reconnect_timeout: None,
+            publisher_stats: PublisherStats::default(),
+            outbox_task: None,
+            send_task: None,
+            send_buffer_full: false,
+            shutdown: None,
+            endpoint: 0,
+            untried_endpoints: 0,
             subscriptions: HashMap::new(),
+            subscription_streams: HashMap::new(),
             outstanding_receipts: HashMap::new(),
         }
     }
 }
 
+// The number of bytes `tx` occupies on the wire, give or take header escaping
+fn transmission_length(tx: &Transmission) -> usize {
+    match *tx {
+        HeartBeat => 1,
+        CompleteFrame(ref frame) => frame.count_bytes() + 1,
+    }
+}
+
 // *** Public API ***
 impl Session {
+    // A handle for sending from other tasks or threads while this session is polled for events
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle::new(self.commands.0.clone(), self.handle_receipt_ids.clone())
+    }
     pub fn send_frame(&mut self, fr: Frame) {
+        if self.state.shutdown.is_some() {
+            warn!("Not sending {} frame: the session is shutting down", fr.command);
+            return;
+        }
         self.send(Transmission::CompleteFrame(fr))
     }
     pub fn message<'builder, T: ToFrameBody>(&'builder mut self,
                                              destination: &str,
                                              body_convertible: T)
                                              -> MessageBuilder<'builder> {
-        let send_frame = Frame::send(destination, body_convertible.to_frame_body());
+        let send_frame = Frame::send(destination, body_convertible);
         MessageBuilder::new(self, send_frame)
     }
 
@@ -97,49 +235,236 @@ impl Session {
     }
 
     pub fn unsubscribe(&mut self, sub_id: &str) {
-        self.state.subscriptions.remove(sub_id);
+        if let Some(mut subscription) = self.state.subscriptions.remove(sub_id) {
+            self.nack_unacked(&mut subscription);
+        }
+        self.state.subscription_streams.remove(sub_id);
         let unsubscribe_frame = Frame::unsubscribe(sub_id.as_ref());
         self.send(CompleteFrame(unsubscribe_frame))
     }
 
     pub fn disconnect(&mut self) {
-        self.send_frame(Frame::disconnect());
+        let _ = self.disconnect_with_receipt();
     }
-    pub fn reconnect(&mut self) -> ::std::io::Result<()> {
-        use std::net::ToSocketAddrs;
-        use std::io;
-
+    pub fn disconnect_with_receipt(&mut self) -> ReceiptFuture {
+        let (id, disconnect_frame) = self.disconnect_frame();
+        let future = self.receipt_future(id, disconnect_frame.clone());
+        self.send_frame(disconnect_frame);
+        future
+    }
+    // Closes the connection once everything sent so far has been dealt with: further sends are
+    // refused, every subscription is cancelled and, once the pending writes are flushed and the
+    // outstanding receipts are done, a DISCONNECT is sent and its receipt awaited. The outbox
+    // only waits for the receipt timeout from now on. After `timeout` the connection is closed
+    // anyway. The session must keep being polled meanwhile.
+    pub fn shutdown(&mut self, timeout: Duration) -> Shutdown {
+        let (notify, receiver) = oneshot::channel();
+        self.state.reconnect_timeout = None;
+        if !self.state.connected {
+            // Abandon any connection attempt; there is nothing to wait for
+            self.stream = StreamState::Failed;
+            self.fail_outstanding_receipts();
+            let _ = notify.send(Ok(()));
+            return Shutdown { receiver };
+        }
+        let deadline = match Timeout::new(timeout, &self.hdl) {
+            Ok(deadline) => deadline,
+            Err(e) => {
+                let _ = notify.send(Err(e.into()));
+                return Shutdown { receiver };
+            },
+        };
+        let sub_ids: Vec<String> = self.state.subscriptions.keys().cloned().collect();
+        for sub_id in sub_ids {
+            self.unsubscribe(&sub_id);
+        }
+        // The outbox no longer waits for as long as it takes, but as long as any other receipt
+        if let Some(duration) = self.config.receipt_timeout {
+            for entry in self.state.outstanding_receipts.values_mut() {
+                if entry.reliable {
+                    entry.expiry = Timeout::new(duration, &self.hdl)
+                        .map_err(|e| warn!("Failed to register receipt timeout: {}", e))
+                        .ok();
+                }
+            }
+        }
+        self.state.shutdown = Some(ShutdownState {
+            notify,
+            deadline,
+            receipt_id: None,
+        });
+        self.advance_shutdown();
+        Shutdown { receiver }
+    }
+    pub fn reconnect(&mut self) -> Result<()> {
         info!("Reconnecting...");
 
-        let address = (&self.config.host as &str, self.config.port)
-            .to_socket_addrs()?.nth(0)
-            .ok_or(io::Error::new(io::ErrorKind::Other, "address provided resolved to nothing"))?;
-        self.stream = StreamState::Connecting(TcpStream::connect(&address, &self.hdl));
+        // Every connection negotiates its own version
+        self.wire_version = SharedVersion::default();
+        let stream = transport::connect(&self.config,
+                                        &self.endpoints[self.state.endpoint],
+                                        self.wire_version.clone(),
+                                        &self.hdl)?;
+        self.stream = StreamState::Connecting(stream);
         task::current().notify();
         Ok(())
     }
-    pub fn acknowledge_frame(&mut self, frame: &Frame, which: AckOrNack) {
-        if let Some(header::Ack(ack_id)) = frame.headers.get_ack() {
-            let ack_frame = if let AckOrNack::Ack = which {
-                Frame::ack(ack_id)
-            }
-            else {
-                Frame::nack(ack_id)
-            };
-            self.send_frame(ack_frame);
+    // Counts the messages sent with `MessageBuilder::send_reliably`
+    pub fn publisher_stats(&self) -> PublisherStats {
+        PublisherStats {
+            in_flight: self.outbox_len(),
+            ..self.state.publisher_stats
         }
     }
+    // Ready once `send_reliably` can accept another message. Otherwise the current task is
+    // woken when the broker confirms one.
+    pub fn poll_outbox(&mut self) -> Async<()> {
+        if self.outbox_len() < self.config.outbox_capacity {
+            Async::Ready(())
+        }
+        else {
+            self.state.outbox_task = Some(task::current());
+            Async::NotReady
+        }
+    }
+    // Ready while fewer than `SendBufferCapacity` bytes are waiting for the connection.
+    // Otherwise the current task is woken once they have drained below it.
+    pub fn poll_send_ready(&mut self) -> Async<()> {
+        if self.state.send_buffer_full {
+            self.state.send_task = Some(task::current());
+            Async::NotReady
+        }
+        else {
+            Async::Ready(())
+        }
+    }
+    // The bytes sent but not yet taken by the connection
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+    // The broker the session is connected, or connecting, to
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoints[self.state.endpoint]
+    }
+    // The protocol version agreed with the broker, once connected
+    pub fn version(&self) -> Option<StompVersion> {
+        self.state.version
+    }
+    pub fn acknowledge_frame(&mut self, frame: &Frame, which: AckOrNack) -> Result<()> {
+        self.acknowledge(frame, which, None)
+    }
+    // Acknowledges `frame` along with every message delivered before it on the same
+    // subscription: with a single cumulative ACK in `client` mode, one ACK per message in
+    // `client-individual` mode.
+    pub fn ack_up_to(&mut self, frame: &Frame) -> Result<()> {
+        let missing = |header: &str| Error::Protocol {
+            message: format!("cannot acknowledge a frame without a '{}' header", header),
+            frame: Some(frame.clone()),
+        };
+        let header::Subscription(sub_id) = frame.headers.get_subscription()
+            .ok_or_else(|| missing("subscription"))?;
+        let header::MessageId(message_id) = frame.headers.get_message_id()
+            .ok_or_else(|| missing("message-id"))?;
+        let (ack_mode, pending) = match self.state.subscriptions.get(sub_id) {
+            Some(subscription) => {
+                (subscription.ack_mode, subscription.unacked_up_to(message_id).map(|f| f.to_vec()))
+            },
+            None => return Err(Error::protocol(format!("unknown subscription '{}'", sub_id))),
+        };
+        match ack_mode {
+            AckMode::Auto => Ok(()),
+            AckMode::Client => self.acknowledge(frame, AckOrNack::Ack, None),
+            AckMode::ClientIndividual => {
+                let pending = pending.ok_or_else(|| {
+                    Error::protocol(format!("message '{}' is not awaiting acknowledgement", message_id))
+                })?;
+                for message in pending {
+                    self.acknowledge(&message, AckOrNack::Ack, None)?;
+                }
+                Ok(())
+            },
+        }
+    }
+    // The number of messages delivered on a subscription that have been neither acked nor nacked
+    pub fn unacked_count(&self, sub_id: &str) -> usize {
+        self.state.subscriptions.get(sub_id).map_or(0, |s| s.unacked_count())
+    }
 }
 // *** pub(crate) API ***
 impl Session {
-    pub(crate) fn new(config: SessionConfig, stream: TcpStreamNew, hdl: Handle) -> Self {
+    // `stream` connects to the endpoint at `endpoint`
+    pub(crate) fn new(config: SessionConfig,
+                      endpoints: Vec<Endpoint>,
+                      endpoint: usize,
+                      stream: TransportFuture,
+                      wire_version: SharedVersion,
+                      hdl: Handle)
+                      -> Self {
+        let mut state = SessionState::new();
+        state.endpoint = endpoint;
+        state.untried_endpoints = endpoints.len() - 1;
         Self {
-            config, hdl,
-            state: SessionState::new(),
+            config, endpoints, hdl, wire_version, state,
+            send_queue: VecDeque::new(),
+            buffered_bytes: 0,
+            flushed: true,
             events: vec![],
+            commands: mpsc::unbounded(),
+            handle_receipt_ids: Arc::new(AtomicUsize::new(0)),
             stream: StreamState::Connecting(stream)
         }
     }
+    pub(crate) fn acknowledge(&mut self,
+                              frame: &Frame,
+                              which: AckOrNack,
+                              transaction_id: Option<&str>)
+                              -> Result<()> {
+        if self.state.shutdown.is_some() {
+            return Err(Error::SessionClosed);
+        }
+        let acknowledgement = self.acknowledgement(frame, which, transaction_id)?;
+        self.send_frame(acknowledgement);
+        Ok(())
+    }
+    // Builds the ACK or NACK for `frame` and stops tracking the messages it settles. Acks sent
+    // as part of a transaction are assumed to be committed.
+    fn acknowledgement(&mut self,
+                       frame: &Frame,
+                       which: AckOrNack,
+                       transaction_id: Option<&str>)
+                       -> Result<Frame> {
+        let version = match self.state.version {
+            Some(version) if self.state.connected => version,
+            _ => return Err(Error::NotConnected),
+        };
+        let acknowledgement = Frame::acknowledgement(frame, which, version, transaction_id)?;
+        let sub_id = frame.headers.get_subscription().map(|s| s.0);
+        let message_id = frame.headers.get_message_id().map(|m| m.0);
+        if let (Some(sub_id), Some(message_id)) = (sub_id, message_id) {
+            if let Some(subscription) = self.state.subscriptions.get_mut(sub_id) {
+                subscription.settle(message_id);
+            }
+        }
+        Ok(acknowledgement)
+    }
+    // NACKs whatever is still unacknowledged so the broker can redeliver it elsewhere
+    fn nack_unacked(&mut self, subscription: &mut Subscription) {
+        let unacked = subscription.take_unacked();
+        let version = match self.state.version {
+            Some(version) if self.state.connected => version,
+            _ => return,
+        };
+        let to_nack = match subscription.ack_mode {
+            AckMode::Client => &unacked[unacked.len().saturating_sub(1)..],
+            _ => &unacked[..],
+        };
+        for message in to_nack {
+            match Frame::acknowledgement(message, AckOrNack::Nack, version, None) {
+                Ok(nack) => self.send(CompleteFrame(nack)),
+                Err(e) => debug!("Not returning unacknowledged message: {}", e),
+            }
+        }
+    }
     pub(crate) fn generate_transaction_id(&mut self) -> u32 {
         let id = self.state.next_transaction_id;
         self.state.next_transaction_id += 1;
@@ -157,19 +482,106 @@ impl Session {
         self.state.next_receipt_id += 1;
         id
     }
+
+    // Starts waiting for a receipt, expiring it after the configured receipt timeout
+    pub(crate) fn track_receipt(&mut self, id: String, mut receipt: OutstandingReceipt) {
+        receipt.sequence = self.state.next_receipt_sequence;
+        self.state.next_receipt_sequence = self.state.next_receipt_sequence.wrapping_add(1);
+        // Reliable messages wait for as long as it takes
+        let timeout = if receipt.reliable { None } else { self.config.receipt_timeout };
+        receipt.expiry = timeout.and_then(|duration| {
+            Timeout::new(duration, &self.hdl)
+                .map_err(|e| warn!("Failed to register receipt timeout: {}", e))
+                .ok()
+        });
+        self.state.outstanding_receipts.insert(id, receipt);
+    }
+
+    // Tracks a receipt request and returns a future that resolves once it is answered
+    pub(crate) fn receipt_future(&mut self, id: String, original_frame: Frame) -> ReceiptFuture {
+        let (notify, receiver) = oneshot::channel();
+        if self.shutting_down() {
+            // The frame will not be sent, so there is no receipt to wait for
+            let _ = notify.send(Err(Error::SessionClosed));
+            return ReceiptFuture::new(id, receiver);
+        }
+        let mut outstanding = OutstandingReceipt::new(original_frame);
+        outstanding.notify = Some(notify);
+        self.track_receipt(id.clone(), outstanding);
+        ReceiptFuture::new(id, receiver)
+    }
+
+    pub(crate) fn shutting_down(&self) -> bool {
+        self.state.shutdown.is_some()
+    }
+
+    pub(crate) fn outbox_len(&self) -> usize {
+        self.state.outstanding_receipts.values().filter(|entry| entry.reliable).count()
+    }
+
+    // Adds a message to the outbox, sending it straight away if the session is connected
+    pub(crate) fn publish(&mut self, id: String, frame: Frame) -> Result<()> {
+        if self.state.shutdown.is_some() {
+            return Err(Error::SessionClosed);
+        }
+        if self.outbox_len() >= self.config.outbox_capacity {
+            return Err(Error::OutboxFull);
+        }
+        let connected = self.state.connected;
+        let mut entry = OutstandingReceipt::new(frame.clone());
+        entry.reliable = true;
+        entry.transmissions = if connected { 1 } else { 0 };
+        self.track_receipt(id, entry);
+        if connected {
+            self.send_frame(frame);
+        }
+        Ok(())
+    }
 }
 // *** Internal API ***
 impl Session {
     fn _send(&mut self, tx: Transmission) -> Result<()> {
-        if let StreamState::Connected(ref mut st) = self.stream {
-            st.start_send(tx)?;
-            st.poll_complete()?;
+        if let StreamState::Connected(_) = self.stream {
+            self.buffered_bytes += transmission_length(&tx);
+            self.send_queue.push_back(tx);
+            self.flush_send_queue()
         }
         else {
             warn!("sending {:?} whilst disconnected", tx);
+            Ok(())
         }
+    }
+    // Hands queued transmissions to the connection for as long as it accepts them
+    fn flush_send_queue(&mut self) -> Result<()> {
+        if let StreamState::Connected(ref mut st) = self.stream {
+            while let Some(tx) = self.send_queue.pop_front() {
+                let length = transmission_length(&tx);
+                match st.start_send(tx)? {
+                    AsyncSink::Ready => self.buffered_bytes -= length,
+                    AsyncSink::NotReady(tx) => {
+                        self.send_queue.push_front(tx);
+                        break;
+                    },
+                }
+            }
+            self.flushed = st.poll_complete()?.is_ready();
+        }
+        self.update_send_buffer();
         Ok(())
     }
+    fn update_send_buffer(&mut self) {
+        let full = self.buffered_bytes >= self.config.send_buffer_capacity;
+        if full && !self.state.send_buffer_full {
+            debug!("Send buffer is full: {} bytes buffered", self.buffered_bytes);
+            self.events.push(SessionEvent::SendBufferFull { buffered: self.buffered_bytes });
+        }
+        if !full {
+            if let Some(task) = self.state.send_task.take() {
+                task.notify();
+            }
+        }
+        self.state.send_buffer_full = full;
+    }
     fn send(&mut self, tx: Transmission) {
         if let Err(e) = self._send(tx) {
             self.on_disconnect(DisconnectionReason::SendFailed(e));
@@ -227,43 +639,241 @@ impl Session {
     }
     fn on_disconnect(&mut self, reason: DisconnectionReason) {
         info!("Disconnected.");
+        let should_reconnect = self.state.shutdown.is_none() && match reason {
+            DisconnectionReason::HeartbeatTimeout |
+            DisconnectionReason::ClosedByOtherSide => true,
+            DisconnectionReason::BrokerError(_) => {
+                self.config.reconnect.is_some_and(|policy| policy.on_broker_error)
+            },
+            DisconnectionReason::ConnectFailed(_) => self.state.reconnect_attempts > 0,
+            _ => false
+        };
+        let fail_over = self.state.shutdown.is_none()
+            && self.state.untried_endpoints > 0
+            && matches!(reason, DisconnectionReason::ConnectFailed(_));
+        if let Some(shutdown) = self.state.shutdown.take() {
+            let result = match reason {
+                DisconnectionReason::Requested => Ok(()),
+                _ => Err(Error::SessionClosed),
+            };
+            let _ = shutdown.notify.send(result);
+        }
         self.events.push(SessionEvent::Disconnected(reason));
         if let StreamState::Connected(ref mut strm) = self.stream {
-            let _ = strm.get_mut().shutdown(::std::net::Shutdown::Both);
+            let _ = strm.close();
         }
         self.stream = StreamState::Failed;
+        self.state.connected = false;
+        // Whatever the old connection did not take is lost with it
+        self.send_queue.clear();
+        self.buffered_bytes = 0;
+        self.flushed = true;
+        self.update_send_buffer();
+        // The broker takes back anything that was not acknowledged on the old connection
+        for subscription in self.state.subscriptions.values_mut() {
+            subscription.take_unacked();
+        }
         self.state.tx_heartbeat_timeout = None;
         self.state.rx_heartbeat_timeout = None;
+        if should_reconnect && !fail_over {
+            self.schedule_reconnect();
+        }
+        let resend = self.config.pending_receipts == PendingReceiptPolicy::Resend
+            && (self.state.reconnect_timeout.is_some() || fail_over);
+        if resend {
+            // Sending a DISCONNECT again would close the new connection too
+            self.state.outstanding_receipts.retain(|_, entry| {
+                let disconnect = matches!(entry.original_frame.command, Command::Disconnect);
+                if disconnect {
+                    entry.resolve(Err(Error::SessionClosed));
+                }
+                !disconnect
+            });
+        }
+        else {
+            self.fail_outstanding_receipts();
+        }
+        if fail_over {
+            self.fail_over();
+        }
     }
-    fn on_stream_ready(&mut self) {
-        debug!("Stream ready!");
-        // Add credentials to the header list if specified
-        match self.config.credentials.clone() { // TODO: Refactor to avoid clone
-            Some(credentials) => {
-                debug!("Using provided credentials: login '{}', passcode '{}'",
-                       credentials.login,
-                       credentials.passcode);
-                let mut headers = &mut self.config.headers;
-                headers.push(Header::new("login", &credentials.login));
-                headers.push(Header::new("passcode", &credentials.passcode));
+    // Moves straight on to the next endpoint after failing to connect
+    fn fail_over(&mut self) {
+        self.state.untried_endpoints -= 1;
+        self.state.endpoint = (self.state.endpoint + 1) % self.endpoints.len();
+        info!("Failing over to {}", self.endpoint());
+        if let Err(e) = self.reconnect() {
+            self.on_disconnect(DisconnectionReason::ConnectFailed(e));
+        }
+    }
+    // A DISCONNECT frame asking for a receipt, and the receipt's id
+    fn disconnect_frame(&mut self) -> (String, Frame) {
+        let id = format!("disconnect/{}", self.generate_receipt_id());
+        let mut frame = Frame::disconnect();
+        frame.headers.push(Header::new("receipt", &id));
+        (id, frame)
+    }
+    fn poll_shutdown(&mut self) -> Result<()> {
+        let expired = match self.state.shutdown {
+            Some(ref mut shutdown) => shutdown.deadline.poll()?.is_ready(),
+            None => return Ok(()),
+        };
+        if !expired {
+            self.advance_shutdown();
+            return Ok(());
+        }
+        if let Some(shutdown) = self.state.shutdown.take() {
+            let waiting_for = shutdown.receipt_id
+                .or_else(|| self.state.outstanding_receipts.keys().next().cloned())
+                .unwrap_or_default();
+            warn!("Timed out shutting down; closing the connection");
+            let _ = shutdown.notify.send(Err(Error::ReceiptTimeout(waiting_for)));
+        }
+        // Nothing will be sent again, so the outbox is given up on as well
+        self.state.outstanding_receipts.retain(|id, entry| {
+            if entry.reliable {
+                entry.resolve(Err(Error::ReceiptTimeout(id.clone())));
+            }
+            !entry.reliable
+        });
+        self.on_disconnect(DisconnectionReason::Requested);
+        Ok(())
+    }
+    // Sends the DISCONNECT once nothing else is pending and the connection has written
+    // everything out
+    fn advance_shutdown(&mut self) {
+        let ready = match self.state.shutdown {
+            Some(ref shutdown) => shutdown.receipt_id.is_none()
+                && self.send_queue.is_empty()
+                && self.flushed
+                && self.state.outstanding_receipts.is_empty(),
+            None => false,
+        };
+        if !ready {
+            return;
+        }
+        let (id, frame) = self.disconnect_frame();
+        self.track_receipt(id.clone(), OutstandingReceipt::new(frame.clone()));
+        if let Some(ref mut shutdown) = self.state.shutdown {
+            shutdown.receipt_id = Some(id);
+        }
+        self.send(CompleteFrame(frame));
+    }
+    fn fail_outstanding_receipts(&mut self) {
+        self.state.outstanding_receipts.retain(|_, entry| {
+            if !entry.reliable {
+                entry.resolve(Err(Error::SessionClosed));
+            }
+            entry.reliable
+        });
+    }
+    // Sends the outbox, plus any other frames awaiting a receipt if `all` is set
+    fn resend_outstanding_receipts(&mut self, all: bool) {
+        let mut pending: Vec<(u32, Frame)> = vec![];
+        for entry in self.state.outstanding_receipts.values_mut() {
+            if !entry.reliable && !all {
+                continue;
             }
-            None => debug!("No credentials supplied."),
+            if entry.reliable && entry.transmissions > 0 {
+                self.state.publisher_stats.retried += 1;
+            }
+            entry.transmissions += 1;
+            pending.push((entry.sequence, entry.original_frame.clone()));
         }
-
-        let connection::HeartBeat(client_tx_ms, client_rx_ms) = self.config.heartbeat;
-        let heart_beat_string = format!("{},{}", client_tx_ms, client_rx_ms);
-        debug!("Using heartbeat: {},{}", client_tx_ms, client_rx_ms);
-        self.config.headers.push(Header::new("heart-beat", heart_beat_string.as_ref()));
-
-        let connect_frame = Frame {
-            command: Command::Connect,
-            headers: self.config.headers.clone(), /* Cloned to allow this to be re-used */
-            body: Vec::new(),
+        pending.sort_by_key(|&(sequence, _)| sequence);
+        if !pending.is_empty() {
+            debug!("Re-sending {} frame(s) awaiting a receipt", pending.len());
+        }
+        for (_, frame) in pending {
+            self.send_frame(frame);
+        }
+    }
+    fn expire_receipts(&mut self) -> Result<()> {
+        let mut expired = vec![];
+        for (id, entry) in self.state.outstanding_receipts.iter_mut() {
+            if let Some(ref mut expiry) = entry.expiry {
+                if expiry.poll()?.is_ready() {
+                    expired.push(id.clone());
+                }
+            }
+        }
+        for id in expired {
+            if let Some(mut entry) = self.state.outstanding_receipts.remove(&id) {
+                warn!("Timed out waiting for receipt '{}'", id);
+                entry.resolve(Err(Error::ReceiptTimeout(id.clone())));
+                self.events.push(SessionEvent::ReceiptTimeout {
+                    id,
+                    original: entry.original_frame
+                });
+            }
+        }
+        Ok(())
+    }
+    fn schedule_reconnect(&mut self) {
+        let policy = match self.config.reconnect {
+            Some(policy) => policy,
+            None => return
         };
-
+        let attempt = self.state.reconnect_attempts + 1;
+        if !policy.allows_attempt(attempt) {
+            warn!("Giving up on reconnecting after {} attempts.", attempt - 1);
+            self.state.reconnect_attempts = 0;
+            return;
+        }
+        let delay = policy.delay(attempt);
+        debug!("Scheduling reconnection attempt #{} in {:?}", attempt, delay);
+        match Timeout::new(delay, &self.hdl) {
+            Ok(timeout) => self.state.reconnect_timeout = Some(timeout),
+            Err(e) => warn!("Failed to schedule reconnection: {}", e)
+        }
+    }
+    fn on_reconnect_timeout(&mut self) {
+        self.state.reconnect_timeout = None;
+        self.state.reconnect_attempts += 1;
+        // Each attempt starts with the endpoint after the last one tried
+        self.state.endpoint = (self.state.endpoint + 1) % self.endpoints.len();
+        self.state.untried_endpoints = self.endpoints.len() - 1;
+        let attempt = self.state.reconnect_attempts;
+        self.events.push(SessionEvent::Reconnecting { attempt });
+        if let Err(e) = self.reconnect() {
+            self.on_disconnect(DisconnectionReason::ConnectFailed(e));
+        }
+    }
+    fn replay_subscriptions(&mut self) {
+        let frames: Vec<Frame> = self.state.subscriptions.values()
+            .map(|sub| {
+                let mut frame = Frame::subscribe(&sub.id, &sub.destination, sub.ack_mode);
+                frame.headers.concat(&mut sub.headers.clone());
+                frame
+            })
+            .collect();
+        debug!("Re-issuing {} subscription(s)", frames.len());
+        for frame in frames {
+            self.send_frame(frame);
+        }
+    }
+    fn on_stream_ready(&mut self) {
+        debug!("Stream ready!");
+        let connect_frame = self.config.connect_frame();
         self.send_frame(connect_frame);
     }
-    fn on_message(&mut self, frame: Frame) {
+    fn on_message(&mut self, mut frame: Frame) {
+        let sub_id = frame.headers.get_subscription().map(|s| s.0.to_owned());
+        if let Some(subscription) = sub_id.as_ref().and_then(|id| self.state.subscriptions.get_mut(id)) {
+            subscription.track(&frame);
+        }
+        if let Some(sub_id) = sub_id {
+            if let Some(stream) = self.state.subscription_streams.remove(&sub_id) {
+                match stream.unbounded_send(frame) {
+                    Ok(()) => {
+                        self.state.subscription_streams.insert(sub_id, stream);
+                        return;
+                    },
+                    // The stream has been dropped and is unsubscribing
+                    Err(e) => frame = e.into_inner(),
+                }
+            }
+        }
         let mut sub_data = None;
         if let Some(header::Subscription(sub_id)) = frame.headers.get_subscription() {
             if let Some(ref sub) = self.state.subscriptions.get(sub_id) {
@@ -283,6 +893,15 @@ impl Session {
     }
 
     fn on_connected_frame_received(&mut self, connected_frame: Frame) -> Result<()> {
+        let version = match Connection::select_version(&self.config.accepted_versions(), &connected_frame) {
+            Ok(version) => version,
+            Err(e) => {
+                self.on_disconnect(DisconnectionReason::ConnectFailed(e));
+                return Ok(());
+            },
+        };
+        self.wire_version.set(version);
+
         // The Client's requested tx/rx HeartBeat timeouts
         let connection::HeartBeat(client_tx_ms, client_rx_ms) = self.config.heartbeat;
 
@@ -302,10 +921,45 @@ impl Session {
         self.register_tx_heartbeat_timeout()?;
         self.register_rx_heartbeat_timeout()?;
 
+        self.state.version = Some(version);
+        self.state.connected = true;
+        self.state.untried_endpoints = self.endpoints.len() - 1;
+        info!("Connected to {}", self.endpoint());
         self.events.push(SessionEvent::Connected);
 
+        let reconnected = self.state.reconnect_attempts > 0;
+        if reconnected {
+            self.replay_subscriptions();
+        }
+        let resend_all = reconnected
+            && self.config.pending_receipts == PendingReceiptPolicy::Resend;
+        self.resend_outstanding_receipts(resend_all);
+
+        if reconnected {
+            self.events.push(SessionEvent::Reconnected {
+                attempts: self.state.reconnect_attempts
+            });
+            self.state.reconnect_attempts = 0;
+        }
+
         Ok(())
     }
+    fn on_error_frame(&mut self, frame: Frame) {
+        self.events.push(SessionEvent::ErrorFrame(frame.clone()));
+        if !self.state.connected {
+            // An ERROR in reply to CONNECT means the broker refused the session
+            self.on_disconnect(DisconnectionReason::ConnectFailed(Error::Authentication(frame)));
+            return;
+        }
+        let receipt_id = frame.headers.get_receipt_id().map(|r| r.0.to_owned());
+        let entry = receipt_id.and_then(|id| self.state.outstanding_receipts.remove(&id));
+        let error = BrokerError::new(frame, entry.as_ref().map(|entry| entry.original_frame.clone()));
+        if let Some(mut entry) = entry {
+            entry.resolve(Err(Error::Broker(Box::new(error.clone()))));
+        }
+        // The broker closes the connection after an ERROR
+        self.on_disconnect(DisconnectionReason::BrokerError(error));
+    }
     fn handle_receipt(&mut self, frame: Frame) {
         let receipt_id = {
             if let Some(header::ReceiptId(receipt_id)) = frame.headers.get_receipt_id() {
@@ -316,10 +970,13 @@ impl Session {
             }
         };
         if let Some(receipt_id) = receipt_id {
-            if receipt_id == "msg/disconnect" {
-                self.on_disconnect(DisconnectionReason::Requested);
-            }
-            if let Some(entry) = self.state.outstanding_receipts.remove(&receipt_id) {
+            let mut disconnected = false;
+            if let Some(mut entry) = self.state.outstanding_receipts.remove(&receipt_id) {
+                disconnected = matches!(entry.original_frame.command, Command::Disconnect);
+                if entry.reliable {
+                    self.state.publisher_stats.confirmed += 1;
+                }
+                entry.resolve(Ok(frame.clone()));
                 let original_frame = entry.original_frame;
                 self.events.push(SessionEvent::Receipt {
                     id: receipt_id,
@@ -327,19 +984,90 @@ impl Session {
                     receipt: frame
                 });
             }
+            if disconnected {
+                self.on_disconnect(DisconnectionReason::Requested);
+            }
         }
     }
 
+    fn poll_commands(&mut self) {
+        while let Ok(Async::Ready(Some(command))) = self.commands.1.poll() {
+            self.on_handle_command(command);
+        }
+    }
+    fn on_handle_command(&mut self, command: HandleCommand) {
+        match command {
+            HandleCommand::Send(frame, reply) => {
+                let _ = reply.send(self.send_for_handle(frame));
+            },
+            HandleCommand::SendWithReceipt(frame, id, reply) => {
+                if let Err(e) = self.accepting_sends() {
+                    let _ = reply.send(Err(e));
+                    return;
+                }
+                let mut outstanding = OutstandingReceipt::new(frame.clone());
+                outstanding.notify = Some(reply);
+                self.track_receipt(id, outstanding);
+                self.send_frame(frame);
+            },
+            HandleCommand::Subscribe(destination, ack_mode, reply) => {
+                let result = self.accepting_sends()
+                    .map(|_| self.subscription(&destination).with(ack_mode).start());
+                let _ = reply.send(result);
+            },
+            HandleCommand::Unsubscribe(sub_id, reply) => {
+                self.state.subscription_streams.remove(&sub_id);
+                let result = match self.state.subscriptions.remove(&sub_id) {
+                    Some(mut subscription) => {
+                        self.nack_unacked(&mut subscription);
+                        self.send_for_handle(Frame::unsubscribe(&sub_id))
+                    },
+                    // Already gone, e.g. a `SubscriptionStream` dropped after `unsubscribe`
+                    None => Ok(()),
+                };
+                let _ = reply.send(result);
+            },
+            HandleCommand::Acknowledge(frame, which, reply) => {
+                let result = self.acknowledgement(&frame, which, None)
+                    .and_then(|acknowledgement| self.send_for_handle(acknowledgement));
+                let _ = reply.send(result);
+            },
+            HandleCommand::Begin(reply) => {
+                let transaction_id = format!("tx/{}", self.generate_transaction_id());
+                let result = self.send_for_handle(Frame::begin(&transaction_id));
+                let _ = reply.send(result.map(|_| transaction_id));
+            },
+            HandleCommand::Commit(transaction_id, reply) => {
+                let _ = reply.send(self.send_for_handle(Frame::commit(&transaction_id)));
+            },
+            HandleCommand::Abort(transaction_id, reply) => {
+                let _ = reply.send(self.send_for_handle(Frame::abort(&transaction_id)));
+            },
+        }
+    }
+    // Unlike `send`, reports to the caller whether the frame could be written
+    fn accepting_sends(&self) -> Result<()> {
+        if self.state.shutdown.is_some() {
+            Err(Error::SessionClosed)
+        }
+        else if !self.state.connected {
+            Err(Error::NotConnected)
+        }
+        else {
+            Ok(())
+        }
+    }
+    fn send_for_handle(&mut self, frame: Frame) -> Result<()> {
+        self.accepting_sends()?;
+        if let Err(e) = self._send(CompleteFrame(frame)) {
+            self.on_disconnect(DisconnectionReason::SendFailed(e));
+            return Err(Error::NotConnected);
+        }
+        Ok(())
+    }
+
     fn poll_stream_complete(&mut self) {
-        let res = {
-            if let StreamState::Connected(ref mut fr) = self.stream {
-                fr.poll_complete()
-            }
-            else {
-                Ok(Async::NotReady)
-            }
-        };
-        if let Err(e) = res {
+        if let Err(e) = self.flush_send_queue() {
             self.on_disconnect(DisconnectionReason::SendFailed(e));
         }
     }
@@ -361,6 +1089,10 @@ impl Session {
                             self.stream = Connected(fr);
                             return Async::NotReady;
                         },
+                        Err(Error::FrameTooLarge(message)) => {
+                            self.on_disconnect(DisconnectionReason::FrameTooLarge(message));
+                            return Async::NotReady;
+                        },
                         Err(e) => {
                             self.on_disconnect(DisconnectionReason::RecvFailed(e));
                             return Async::NotReady;
@@ -369,8 +1101,7 @@ impl Session {
                 },
                 Connecting(mut tsn) => {
                     match tsn.poll() {
-                        Ok(Async::Ready(s)) => {
-                            let fr = s.framed(Codec);
+                        Ok(Async::Ready(fr)) => {
                             self.stream = Connected(fr);
                             self.on_stream_ready();
                         },
@@ -393,9 +1124,13 @@ impl Session {
 }
 #[derive(Debug)]
 pub enum DisconnectionReason {
-    RecvFailed(::std::io::Error),
-    ConnectFailed(::std::io::Error),
-    SendFailed(::std::io::Error),
+    RecvFailed(Error),
+    // The broker sent a frame that exceeded the configured `FrameLimits`
+    FrameTooLarge(String),
+    // The broker sent an ERROR frame and closed the connection
+    BrokerError(BrokerError),
+    ConnectFailed(Error),
+    SendFailed(Error),
     ClosedByOtherSide,
     HeartbeatTimeout,
     Requested
@@ -408,6 +1143,10 @@ pub enum SessionEvent {
         original: Frame,
         receipt: Frame
     },
+    ReceiptTimeout {
+        id: String,
+        original: Frame
+    },
     Message {
         destination: String,
         ack_mode: AckMode,
@@ -415,23 +1154,45 @@ pub enum SessionEvent {
     },
     SubscriptionlessFrame(Frame),
     UnknownFrame(Frame),
-    Disconnected(DisconnectionReason)
+    Disconnected(DisconnectionReason),
+    Reconnecting {
+        attempt: u32
+    },
+    Reconnected {
+        attempts: u32
+    },
+    // The connection is not keeping up: `buffered` bytes are waiting to be sent, which is at
+    // least the `SendBufferCapacity`
+    SendBufferFull {
+        buffered: usize
+    }
 }
 pub(crate) enum StreamState {
-    Connected(Framed<TcpStream, Codec>),
-    Connecting(TcpStreamNew),
+    Connected(BoxedFrameTransport),
+    Connecting(TransportFuture),
     Failed
 }
 pub struct Session {
     config: SessionConfig,
+    endpoints: Vec<Endpoint>,
     pub(crate) state: SessionState,
     stream: StreamState,
+    // The version the codec escapes headers for
+    wire_version: SharedVersion,
     hdl: Handle,
-    events: Vec<SessionEvent>
+    // Transmissions the connection has not accepted yet, and their size
+    send_queue: VecDeque<Transmission>,
+    buffered_bytes: usize,
+    // Whether the connection has written out everything it accepted
+    flushed: bool,
+    events: Vec<SessionEvent>,
+    commands: (mpsc::UnboundedSender<HandleCommand>, mpsc::UnboundedReceiver<HandleCommand>),
+    // Shared by every handle so that their receipt ids never collide
+    handle_receipt_ids: Arc<AtomicUsize>,
 }
 impl Stream for Session {
     type Item = SessionEvent;
-    type Error = ::std::io::Error;
+    type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         use frame::Transmission::*;
@@ -446,7 +1207,7 @@ impl Stream for Session {
                     debug!("Received frame: {:?}", frame);
                     self.on_recv_data()?;
                     match frame.command {
-                        Command::Error => self.events.push(SessionEvent::ErrorFrame(frame)),
+                        Command::Error => self.on_error_frame(frame),
                         Command::Receipt => self.handle_receipt(frame),
                         Command::Connected => self.on_connected_frame_received(frame)?,
                         Command::Message => self.on_message(frame),
@@ -472,10 +1233,36 @@ impl Stream for Session {
 
         if let Async::Ready(_) = txh {
             self.reply_to_heartbeat()?;
+            // the new timeout only wakes us once it has been polled
+            task::current().notify();
         }
 
+        let rch = self.state.reconnect_timeout
+            .as_mut()
+            .map(|t| t.poll())
+            .unwrap_or(Ok(Async::NotReady))?;
+
+        if let Async::Ready(_) = rch {
+            self.on_reconnect_timeout();
+        }
+
+        self.expire_receipts()?;
+
         self.poll_stream_complete();
 
+        self.poll_shutdown()?;
+
+        // Commands from handles wait in their channel while the send buffer is full
+        if !self.state.send_buffer_full {
+            self.poll_commands();
+        }
+
+        if self.outbox_len() < self.config.outbox_capacity {
+            if let Some(task) = self.state.outbox_task.take() {
+                task.notify();
+            }
+        }
+
         if self.events.len() > 0 {
             if self.events.len() > 1 {
                 // make sure we get polled again, so we can get rid of our other events
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use futures::{Async, Future, Poll};
+use futures::sync::{mpsc, oneshot};
+use error::{Error, Result};
+use frame::{Frame, ToFrameBody};
+use header::Header;
+use session::ReceiptFuture;
+use subscription::{AckMode, AckOrNack};
+
+pub(crate) type ReplyTo<T> = oneshot::Sender<Result<T>>;
+
+// Work queued by a `SessionHandle`; it is carried out the next time the session is polled
+pub(crate) enum HandleCommand {
+    Send(Frame, ReplyTo<()>),
+    SendWithReceipt(Frame, String, ReplyTo<Frame>),
+    Subscribe(String, AckMode, ReplyTo<String>),
+    Unsubscribe(String, ReplyTo<()>),
+    Acknowledge(Frame, AckOrNack, ReplyTo<()>),
+    Begin(ReplyTo<String>),
+    Commit(String, ReplyTo<()>),
+    Abort(String, ReplyTo<()>),
+}
+
+// A `Clone + Send` way of using a session from other tasks or threads while its owner polls it
+// for events. Each operation resolves once the session has carried it out.
+#[derive(Clone)]
+pub struct SessionHandle {
+    commands: mpsc::UnboundedSender<HandleCommand>,
+    next_receipt_id: Arc<AtomicUsize>,
+}
+
+impl SessionHandle {
+    pub(crate) fn new(commands: mpsc::UnboundedSender<HandleCommand>, next_receipt_id: Arc<AtomicUsize>) -> SessionHandle {
+        SessionHandle {
+            commands,
+            next_receipt_id,
+        }
+    }
+
+    pub fn send<T: ToFrameBody>(&self, destination: &str, body_convertible: T) -> Reply<()> {
+        self.send_frame(Frame::send(destination, body_convertible))
+    }
+    pub fn send_frame(&self, frame: Frame) -> Reply<()> {
+        self.request(|reply| HandleCommand::Send(frame, reply))
+    }
+    pub fn send_with_receipt(&self, mut frame: Frame) -> ReceiptFuture {
+        let id = format!("handle/{}", self.next_receipt_id.fetch_add(1, Ordering::Relaxed));
+        frame.headers.push(Header::new("receipt", id.as_ref()));
+        let Reply { receiver } = self.request(|reply| HandleCommand::SendWithReceipt(frame, id.clone(), reply));
+        ReceiptFuture::new(id, receiver)
+    }
+    // Resolves to the id of the new subscription
+    pub fn subscribe(&self, destination: &str, ack_mode: AckMode) -> Reply<String> {
+        self.request(|reply| HandleCommand::Subscribe(destination.to_owned(), ack_mode, reply))
+    }
+    pub fn unsubscribe(&self, sub_id: &str) -> Reply<()> {
+        self.request(|reply| HandleCommand::Unsubscribe(sub_id.to_owned(), reply))
+    }
+    pub fn ack(&self, frame: &Frame) -> Reply<()> {
+        self.request(|reply| HandleCommand::Acknowledge(frame.clone(), AckOrNack::Ack, reply))
+    }
+    pub fn nack(&self, frame: &Frame) -> Reply<()> {
+        self.request(|reply| HandleCommand::Acknowledge(frame.clone(), AckOrNack::Nack, reply))
+    }
+    // Resolves to the id of the new transaction, for use in the `transaction` header
+    pub fn begin(&self) -> Reply<String> {
+        self.request(HandleCommand::Begin)
+    }
+    pub fn commit(&self, transaction_id: &str) -> Reply<()> {
+        self.request(|reply| HandleCommand::Commit(transaction_id.to_owned(), reply))
+    }
+    pub fn abort(&self, transaction_id: &str) -> Reply<()> {
+        self.request(|reply| HandleCommand::Abort(transaction_id.to_owned(), reply))
+    }
+
+    fn request<T, F>(&self, command: F) -> Reply<T>
+        where F: FnOnce(ReplyTo<T>) -> HandleCommand
+    {
+        let (reply, receiver) = oneshot::channel();
+        // If the session is gone the command is dropped along with `reply`, failing the `Reply`
+        let _ = self.commands.unbounded_send(command(reply));
+        Reply { receiver }
+    }
+}
+
+// The outcome of an operation requested through a `SessionHandle`
+pub struct Reply<T> {
+    receiver: oneshot::Receiver<Result<T>>,
+}
+
+impl<T> Future for Reply<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<T, Error> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(Ok(value))) => Ok(Async::Ready(value)),
+            Ok(Async::Ready(Err(e))) => Err(e),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(Error::SessionClosed),
+        }
+    }
+}
@@ -1,4 +1,10 @@
+use futures::{Async, Poll, Stream};
+use futures::sync::mpsc;
+use error::Error;
+use bytes::Bytes;
+use frame::Frame;
 use header::HeaderList;
+use session_handle::{Reply, SessionHandle};
 
 #[derive(Copy, Clone)]
 pub enum AckMode {
@@ -28,6 +34,8 @@ pub struct Subscription {
     pub destination: String,
     pub ack_mode: AckMode,
     pub headers: HeaderList,
+    // The headers of delivered messages that still need an ACK or NACK, oldest first
+    unacked: Vec<Frame>,
 }
 
 
@@ -42,6 +50,147 @@ impl Subscription {
             destination: destination.to_string(),
             ack_mode: ack_mode,
             headers: headers,
+            unacked: Vec::new(),
         }
     }
+
+    // The number of delivered messages that have been neither acked nor nacked
+    pub fn unacked_count(&self) -> usize {
+        self.unacked.len()
+    }
+
+    pub(crate) fn track(&mut self, message: &Frame) {
+        if let AckMode::Auto = self.ack_mode {
+            return;
+        }
+        // Only the headers are needed to acknowledge the message later
+        self.unacked.push(Frame {
+            command: message.command,
+            headers: message.headers.clone(),
+            body: Bytes::new(),
+        });
+    }
+
+    // The tracked messages up to and including `message_id`, if it is being tracked
+    pub(crate) fn unacked_up_to(&self, message_id: &str) -> Option<&[Frame]> {
+        self.position(message_id).map(|position| &self.unacked[..position + 1])
+    }
+
+    // Forgets `message_id` once it has been acked or nacked. In `client` mode this settles every
+    // earlier message too.
+    pub(crate) fn settle(&mut self, message_id: &str) {
+        if let Some(position) = self.position(message_id) {
+            match self.ack_mode {
+                AckMode::Client => {
+                    self.unacked.drain(..position + 1);
+                },
+                _ => {
+                    self.unacked.remove(position);
+                },
+            }
+        }
+    }
+
+    pub(crate) fn take_unacked(&mut self) -> Vec<Frame> {
+        ::std::mem::take(&mut self.unacked)
+    }
+
+    fn position(&self, message_id: &str) -> Option<usize> {
+        self.unacked.iter().position(|frame| {
+            frame.headers.get_message_id().map(|m| m.0) == Some(message_id)
+        })
+    }
+}
+
+// The messages of a single subscription, as returned by `SubscriptionBuilder::start_stream`.
+// Frames only arrive while the session itself is being polled.
+pub struct SubscriptionStream {
+    id: String,
+    frames: mpsc::UnboundedReceiver<Frame>,
+    handle: SessionHandle,
+}
+
+impl SubscriptionStream {
+    pub(crate) fn new(id: String,
+                      frames: mpsc::UnboundedReceiver<Frame>,
+                      handle: SessionHandle)
+                      -> SubscriptionStream {
+        SubscriptionStream {
+            id,
+            frames,
+            handle,
+        }
+    }
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Stream for SubscriptionStream {
+    type Item = ReceivedMessage;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ReceivedMessage>, Error> {
+        match self.frames.poll() {
+            Ok(Async::Ready(Some(frame))) => Ok(Async::Ready(Some(ReceivedMessage {
+                frame,
+                handle: self.handle.clone(),
+            }))),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // The subscription ended or the session was dropped
+            Ok(Async::Ready(None)) | Err(()) => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl Drop for SubscriptionStream {
+    fn drop(&mut self) {
+        let _ = self.handle.unsubscribe(&self.id);
+    }
+}
+
+pub struct ReceivedMessage {
+    pub frame: Frame,
+    handle: SessionHandle,
+}
+
+impl ReceivedMessage {
+    pub fn ack(&self) -> Reply<()> {
+        self.handle.ack(&self.frame)
+    }
+    pub fn nack(&self) -> Reply<()> {
+        self.handle.nack(&self.frame)
+    }
+}
+
+#[cfg(test)]
+fn delivered(subscription: &mut Subscription, message_ids: &[&str]) {
+    for message_id in message_ids {
+        subscription.track(&Frame::message("/queue/a", message_id, &subscription.id.clone(), b"body"));
+    }
+}
+
+#[test]
+fn client_acknowledgements_settle_earlier_messages() {
+    let mut subscription = Subscription::new(0, "/queue/a", AckMode::Client, HeaderList::new());
+    delivered(&mut subscription, &["1", "2", "3"]);
+    assert_eq!(subscription.unacked_up_to("2").map(|frames| frames.len()), Some(2));
+    subscription.settle("2");
+    assert_eq!(subscription.unacked_count(), 1);
+    assert!(subscription.unacked.iter().all(|frame| frame.body.is_empty()));
+}
+
+#[test]
+fn individual_acknowledgements_settle_one_message() {
+    let mut subscription = Subscription::new(0, "/queue/a", AckMode::ClientIndividual, HeaderList::new());
+    delivered(&mut subscription, &["1", "2", "3"]);
+    subscription.settle("2");
+    subscription.settle("unknown");
+    assert_eq!(subscription.unacked_count(), 2);
+    assert_eq!(subscription.take_unacked().len(), 2);
+    assert_eq!(subscription.unacked_count(), 0);
+
+    let mut auto = Subscription::new(1, "/queue/a", AckMode::Auto, HeaderList::new());
+    delivered(&mut auto, &["1"]);
+    assert_eq!(auto.unacked_count(), 0);
 }
@@ -1,5 +1,6 @@
-use session::{Session, ReceiptRequest, OutstandingReceipt};
-use subscription::{Subscription, AckMode};
+use futures::sync::mpsc;
+use session::{Session, ReceiptRequest, ReceiptFuture, OutstandingReceipt, GenerateReceipt};
+use subscription::{Subscription, SubscriptionStream, AckMode};
 use frame::Frame;
 use header::HeaderList;
 use option_setter::OptionSetter;
@@ -26,6 +27,41 @@ impl<'a> SubscriptionBuilder<'a> {
 
     #[allow(dead_code)]
     pub fn start(mut self) -> String {
+        let (id, subscribe_frame) = self.subscribe();
+        self.track_receipt(subscribe_frame);
+        id
+    }
+
+    // Like `start`, but the subscription's messages are yielded by the returned stream rather
+    // than as `SessionEvent::Message`s. Dropping the stream unsubscribes.
+    pub fn start_stream(mut self) -> SubscriptionStream {
+        let (id, subscribe_frame) = self.subscribe();
+        self.track_receipt(subscribe_frame);
+        let (sender, receiver) = mpsc::unbounded();
+        self.session.state.subscription_streams.insert(id.clone(), sender);
+        SubscriptionStream::new(id, receiver, self.session.handle())
+    }
+
+    pub fn start_with_receipt(self) -> (String, ReceiptFuture) {
+        let mut builder = if self.receipt_request.is_some() { self } else { self.with(GenerateReceipt) };
+        let request = builder.receipt_request.take().unwrap();
+        let (id, subscribe_frame) = builder.subscribe();
+        let future = builder.session.receipt_future(request.id, subscribe_frame);
+        (id, future)
+    }
+
+    fn track_receipt(&mut self, subscribe_frame: Frame) {
+        if let Some(request) = self.receipt_request.take().filter(|_| !self.session.shutting_down()) {
+            self.session.track_receipt(
+                request.id,
+                OutstandingReceipt::new(
+                    subscribe_frame,
+                )
+            );
+        }
+    }
+
+    fn subscribe(&mut self) -> (String, Frame) {
         let next_id = self.session.generate_subscription_id();
         let subscription = Subscription::new(next_id,
                                              &self.destination,
@@ -43,16 +79,7 @@ impl<'a> SubscriptionBuilder<'a> {
                subscription.id);
         let id_to_return = subscription.id.to_string();
         self.session.state.subscriptions.insert(subscription.id.to_string(), subscription);
-        if self.receipt_request.is_some() {
-            let request = self.receipt_request.unwrap();
-            self.session.state.outstanding_receipts.insert(
-                request.id,
-                OutstandingReceipt::new(
-                    subscribe_frame.clone(),
-                )
-            );
-        }
-        id_to_return
+        (id_to_return, subscribe_frame)
     }
 
     #[allow(dead_code)]
@@ -0,0 +1,105 @@
+use native_tls::{self, Certificate, Identity};
+use tokio_tls::TlsConnector;
+use error::{Error, Result};
+
+#[derive(Clone)]
+enum ClientIdentity {
+    Pkcs12 {
+        der: Vec<u8>,
+        password: String,
+    },
+    Pkcs8 {
+        certificate_pem: Vec<u8>,
+        key_pem: Vec<u8>,
+    },
+}
+
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    ca_certificates: Vec<Vec<u8>>,
+    identity: Option<ClientIdentity>,
+    pub domain: Option<String>,
+    pub accept_invalid_certs: bool,
+}
+
+impl TlsConfig {
+    pub fn new() -> TlsConfig {
+        TlsConfig::default()
+    }
+
+    // Accepts a PEM bundle containing one or more certificates
+    pub fn ca_bundle(mut self, pem: &[u8]) -> TlsConfig {
+        self.ca_certificates.extend(split_pem_bundle(pem));
+        self
+    }
+
+    pub fn client_pkcs12(mut self, der: &[u8], password: &str) -> TlsConfig {
+        self.identity = Some(ClientIdentity::Pkcs12 {
+            der: der.to_vec(),
+            password: password.to_owned(),
+        });
+        self
+    }
+
+    pub fn client_pkcs8(mut self, certificate_pem: &[u8], key_pem: &[u8]) -> TlsConfig {
+        self.identity = Some(ClientIdentity::Pkcs8 {
+            certificate_pem: certificate_pem.to_vec(),
+            key_pem: key_pem.to_vec(),
+        });
+        self
+    }
+
+    // Overrides the host name used for SNI and certificate validation
+    pub fn domain(mut self, domain: &str) -> TlsConfig {
+        self.domain = Some(domain.to_owned());
+        self
+    }
+
+    // Disables certificate validation entirely. Only use this in development.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> TlsConfig {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    pub(crate) fn connector(&self) -> Result<TlsConnector> {
+        let mut builder = native_tls::TlsConnector::builder();
+        for pem in &self.ca_certificates {
+            builder.add_root_certificate(Certificate::from_pem(pem).map_err(tls_error)?);
+        }
+        match self.identity {
+            Some(ClientIdentity::Pkcs12 { ref der, ref password }) => {
+                builder.identity(Identity::from_pkcs12(der, password).map_err(tls_error)?);
+            },
+            Some(ClientIdentity::Pkcs8 { ref certificate_pem, ref key_pem }) => {
+                builder.identity(Identity::from_pkcs8(certificate_pem, key_pem).map_err(tls_error)?);
+            },
+            None => {}
+        }
+        builder.danger_accept_invalid_certs(self.accept_invalid_certs);
+        let connector = builder.build().map_err(tls_error)?;
+        Ok(TlsConnector::from(connector))
+    }
+}
+
+fn tls_error(error: native_tls::Error) -> Error {
+    Error::transport(error)
+}
+
+fn split_pem_bundle(pem: &[u8]) -> Vec<Vec<u8>> {
+    const END: &str = "-----END CERTIFICATE-----";
+    let text = String::from_utf8_lossy(pem);
+    text.split_terminator(END)
+        .filter(|chunk| chunk.contains("-----BEGIN CERTIFICATE-----"))
+        .map(|chunk| format!("{}{}\n", chunk.trim_start(), END).into_bytes())
+        .collect()
+}
+
+#[test]
+fn split_pem_bundle_finds_every_certificate() {
+    let bundle = b"-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+                   -----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n";
+    let certificates = split_pem_bundle(bundle);
+    assert_eq!(certificates.len(), 2);
+    assert_eq!(certificates[1],
+               b"-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n".to_vec());
+}
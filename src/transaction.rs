@@ -2,7 +2,9 @@ use frame::Frame;
 use frame::ToFrameBody;
 use message_builder::MessageBuilder;
 use header::Header;
-use session::{Session};
+use session::{Session, ReceiptFuture};
+use subscription::AckOrNack;
+use error::Result;
 
 pub struct Transaction<'tx> {
     pub id: String,
@@ -22,11 +24,16 @@ impl<'tx> Transaction<'tx> {
                                              destination: &str,
                                              body_convertible: T)
                                              -> MessageBuilder<'builder> {
-        let mut send_frame = Frame::send(destination, body_convertible.to_frame_body());
+        let mut send_frame = Frame::send(destination, body_convertible);
         send_frame.headers.push(Header::new("transaction", self.id.as_ref()));
         MessageBuilder::new(self.session, send_frame)
     }
 
+    // Acknowledges a message as part of this transaction, so that aborting it undoes the ack
+    pub fn acknowledge_frame(&mut self, frame: &Frame, which: AckOrNack) -> Result<()> {
+        self.session.acknowledge(frame, which, Some(&self.id))
+    }
+
     // TODO: See if it's feasible to do this via command_sender
 
     pub fn begin(&mut self) {
@@ -39,6 +46,15 @@ impl<'tx> Transaction<'tx> {
         self.session.send_frame(commit_frame)
     }
 
+    pub fn commit_with_receipt(self) -> ReceiptFuture {
+        let receipt_id = format!("message/{}", self.session.generate_receipt_id());
+        let mut commit_frame = Frame::commit(self.id.as_ref());
+        commit_frame.headers.push(Header::new("receipt", receipt_id.as_ref()));
+        let future = self.session.receipt_future(receipt_id, commit_frame.clone());
+        self.session.send_frame(commit_frame);
+        future
+    }
+
     pub fn abort(self) {
         let abort_frame = Frame::abort(self.id.as_ref());
         self.session.send_frame(abort_frame)
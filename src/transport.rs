@@ -0,0 +1,120 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use futures::{Future, Sink, Stream};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use codec::{Codec, SharedVersion};
+use connection::Endpoint;
+use error::{Error, Result};
+use frame::Transmission;
+use session_builder::SessionConfig;
+#[cfg(feature = "tls")]
+use tls::TlsConfig;
+#[cfg(feature = "websocket")]
+use websocket;
+
+pub trait Transport: AsyncRead + AsyncWrite {}
+
+impl<T: AsyncRead + AsyncWrite> Transport for T {}
+
+pub type BoxedTransport = Box<dyn Transport>;
+pub type StreamFuture = Box<dyn Future<Item = BoxedTransport, Error = Error>>;
+
+// A connection that carries whole transmissions rather than bytes, so that
+// transports with their own message framing (e.g. WebSockets) can be used
+pub trait FrameTransport: Stream<Item = Transmission, Error = Error>
+    + Sink<SinkItem = Transmission, SinkError = Error> {}
+
+impl<T> FrameTransport for T
+    where T: Stream<Item = Transmission, Error = Error>
+        + Sink<SinkItem = Transmission, SinkError = Error> {}
+
+pub type BoxedFrameTransport = Box<dyn FrameTransport>;
+pub type TransportFuture = Box<dyn Future<Item = BoxedFrameTransport, Error = Error>>;
+
+// `version` is shared with the connection's codec, which escapes headers accordingly.
+// A WebSocket URL in the config takes the place of `endpoint`.
+pub(crate) fn connect(config: &SessionConfig,
+                      endpoint: &Endpoint,
+                      version: SharedVersion,
+                      hdl: &Handle)
+                      -> Result<TransportFuture> {
+    #[cfg(feature = "websocket")]
+    {
+        if let Some(ref url) = config.websocket {
+            return websocket::connect(url, config, version, hdl);
+        }
+    }
+    let stream = connect_stream(config, &endpoint.host, endpoint.port, false, hdl)?;
+    let limits = config.limits;
+    Ok(Box::new(stream.map(move |s| Box::new(s.framed(Codec::with_limits(version, limits))) as BoxedFrameTransport)))
+}
+
+// Opens a byte stream to the given endpoint, using TLS if the session is
+// configured for it or the caller requires it
+pub(crate) fn connect_stream(config: &SessionConfig,
+                             host: &str,
+                             port: u16,
+                             require_tls: bool,
+                             hdl: &Handle)
+                             -> Result<StreamFuture> {
+    let addresses: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+    let tcp = connect_tcp(addresses, hdl).ok_or_else(|| Error::AddressResolution(host.to_owned()))?;
+    connect_tls(config, host, require_tls, tcp)
+}
+
+// Tries each address in turn until one accepts the connection
+fn connect_tcp(addresses: Vec<SocketAddr>, hdl: &Handle) -> Option<Box<dyn Future<Item = TcpStream, Error = Error>>> {
+    let mut addresses = addresses.into_iter();
+    let first = addresses.next()?;
+    let attempt: Box<dyn Future<Item = TcpStream, Error = Error>> =
+        Box::new(TcpStream::connect(&first, hdl).map_err(Error::from));
+    Some(addresses.fold(attempt, |attempt, address| {
+        let hdl = hdl.clone();
+        Box::new(attempt.or_else(move |e| {
+            debug!("Connection failed ({}), trying {}", e, address);
+            TcpStream::connect(&address, &hdl).map_err(Error::from)
+        }))
+    }))
+}
+
+#[cfg(feature = "tls")]
+fn connect_tls<F>(config: &SessionConfig,
+                  host: &str,
+                  require_tls: bool,
+                  tcp: F)
+                  -> Result<StreamFuture>
+    where F: Future<Item = TcpStream, Error = Error> + 'static
+{
+    let default_tls;
+    let tls = match config.tls {
+        Some(ref tls) => tls,
+        None if require_tls => {
+            default_tls = TlsConfig::new();
+            &default_tls
+        },
+        None => return Ok(Box::new(tcp.map(|s| Box::new(s) as BoxedTransport)))
+    };
+    let connector = tls.connector()?;
+    let domain = tls.domain.clone().unwrap_or_else(|| host.to_owned());
+    Ok(Box::new(tcp.and_then(move |s| {
+        debug!("Starting TLS handshake with '{}'", domain);
+        connector.connect(&domain, s)
+            .map(|s| Box::new(s) as BoxedTransport)
+            .map_err(Error::transport)
+    })))
+}
+
+#[cfg(not(feature = "tls"))]
+fn connect_tls<F>(_config: &SessionConfig,
+                  _host: &str,
+                  require_tls: bool,
+                  tcp: F)
+                  -> Result<StreamFuture>
+    where F: Future<Item = TcpStream, Error = Error> + 'static
+{
+    if require_tls {
+        return Err(Error::transport("TLS was requested but the `tls` feature is disabled"));
+    }
+    Ok(Box::new(tcp.map(|s| Box::new(s) as BoxedTransport)))
+}
@@ -0,0 +1,251 @@
+use std::io;
+use error::{Error, Result};
+use bytes::BytesMut;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use tokio_core::reactor::Handle;
+use tokio_io::codec::Decoder;
+use tungstenite::{self, ClientHandshake, HandshakeError, Message, WebSocket};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::MidHandshake;
+use tungstenite::handshake::client::{Request, Response};
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::http::{HeaderValue, Uri};
+use codec::{Codec, SharedVersion};
+use frame::Transmission;
+use session_builder::SessionConfig;
+use transport::{self, BoxedFrameTransport, BoxedTransport, TransportFuture};
+
+pub const SUBPROTOCOL: &str = "v12.stomp";
+
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    pub secure: bool,
+}
+
+pub fn parse_url(url: &str) -> Result<Endpoint> {
+    let uri: Uri = url.parse()
+        .map_err(|e| invalid_url(url, e))?;
+    let secure = match uri.scheme_str() {
+        Some("ws") => false,
+        Some("wss") => true,
+        _ => return Err(invalid_url(url, "expected a ws:// or wss:// scheme")),
+    };
+    let host = uri.host()
+        .ok_or_else(|| invalid_url(url, "missing host"))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_owned();
+    let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+    Ok(Endpoint { host, port, secure })
+}
+
+fn invalid_url<E: ::std::fmt::Display>(url: &str, error: E) -> Error {
+    Error::InvalidUri {
+        uri: url.to_owned(),
+        reason: error.to_string(),
+    }
+}
+
+fn websocket_error(error: tungstenite::Error) -> Error {
+    match error {
+        tungstenite::Error::Io(e) => Error::Io(e),
+        e => Error::transport(e),
+    }
+}
+
+pub(crate) fn connect(url: &str,
+                      config: &SessionConfig,
+                      version: SharedVersion,
+                      hdl: &Handle)
+                      -> Result<TransportFuture> {
+    let endpoint = parse_url(url)?;
+    let codec = Codec::with_limits(version, config.limits);
+    let mut request = url.into_client_request().map_err(websocket_error)?;
+    request.headers_mut()
+        .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(SUBPROTOCOL));
+    let stream = transport::connect_stream(config,
+                                           &endpoint.host,
+                                           endpoint.port,
+                                           endpoint.secure,
+                                           hdl)?;
+    Ok(Box::new(stream
+        .and_then(move |s| Handshake::new(request, s, websocket_config()))
+        .map(move |socket| Box::new(WebSocketTransport::new(socket, codec)) as BoxedFrameTransport)))
+}
+
+// Past `max_write_buffer_size` the socket refuses messages, which then wait in the session's
+// send queue where `SendBufferCapacity` applies, as they do over plain TCP
+fn websocket_config() -> WebSocketConfig {
+    let mut config = WebSocketConfig::default();
+    config.max_write_buffer_size = 2 * config.write_buffer_size;
+    config
+}
+
+enum HandshakeState {
+    Start(Request, BoxedTransport, WebSocketConfig),
+    Handshaking(MidHandshake<ClientHandshake<BoxedTransport>>),
+    Done,
+}
+
+struct Handshake {
+    state: HandshakeState,
+}
+
+impl Handshake {
+    fn new(request: Request, stream: BoxedTransport, config: WebSocketConfig) -> Handshake {
+        Handshake { state: HandshakeState::Start(request, stream, config) }
+    }
+}
+
+impl Future for Handshake {
+    type Item = WebSocket<BoxedTransport>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = match ::std::mem::replace(&mut self.state, HandshakeState::Done) {
+            HandshakeState::Start(request, stream, config) => {
+                tungstenite::client::client_with_config(request, stream, Some(config))
+            },
+            HandshakeState::Handshaking(mid) => mid.handshake(),
+            HandshakeState::Done => panic!("polled a completed WebSocket handshake"),
+        };
+        match result {
+            Ok((socket, response)) => {
+                check_subprotocol(&response)?;
+                debug!("WebSocket handshake complete");
+                Ok(Async::Ready(socket))
+            },
+            Err(HandshakeError::Interrupted(mid)) => {
+                self.state = HandshakeState::Handshaking(mid);
+                Ok(Async::NotReady)
+            },
+            Err(HandshakeError::Failure(e)) => Err(websocket_error(e)),
+        }
+    }
+}
+
+fn check_subprotocol(response: &Response) -> Result<()> {
+    match response.headers().get("Sec-WebSocket-Protocol") {
+        Some(protocol) if protocol != SUBPROTOCOL => {
+            Err(Error::protocol(format!("server selected unsupported sub-protocol {:?}", protocol)))
+        },
+        _ => Ok(()),
+    }
+}
+
+// Carries one STOMP transmission per WebSocket message
+pub struct WebSocketTransport {
+    socket: WebSocket<BoxedTransport>,
+    codec: Codec,
+    read_buffer: BytesMut,
+}
+
+impl WebSocketTransport {
+    fn new(socket: WebSocket<BoxedTransport>, codec: Codec) -> WebSocketTransport {
+        WebSocketTransport {
+            socket,
+            codec,
+            read_buffer: BytesMut::new(),
+        }
+    }
+}
+
+impl WebSocketTransport {
+    // Writes a message the socket refused even with nothing else buffered: like a codec past
+    // its high-water mark, take it anyway rather than refuse it forever
+    fn write_alone(&mut self, message: Message) -> StartSend<Transmission, Error> {
+        let limit = self.socket.get_config().max_write_buffer_size;
+        self.socket.set_config(|config| config.max_write_buffer_size = usize::MAX);
+        let result = self.socket.write(message);
+        self.socket.set_config(|config| config.max_write_buffer_size = limit);
+        match result {
+            Ok(()) => Ok(AsyncSink::Ready),
+            Err(ref e) if would_block(e) => Ok(AsyncSink::Ready),
+            Err(e) => Err(websocket_error(e)),
+        }
+    }
+}
+
+fn would_block(error: &tungstenite::Error) -> bool {
+    match *error {
+        tungstenite::Error::Io(ref e) => e.kind() == io::ErrorKind::WouldBlock,
+        _ => false,
+    }
+}
+
+impl Stream for WebSocketTransport {
+    type Item = Transmission;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Transmission>, Error> {
+        loop {
+            if let Some(transmission) = self.codec.decode(&mut self.read_buffer)? {
+                return Ok(Async::Ready(Some(transmission)));
+            }
+            match self.socket.read() {
+                Ok(Message::Text(text)) => self.read_buffer.extend(text.as_bytes()),
+                Ok(Message::Binary(data)) => self.read_buffer.extend(&data),
+                Ok(Message::Close(_)) => return Ok(Async::Ready(None)),
+                Ok(_) => {},
+                Err(ref e) if would_block(e) => return Ok(Async::NotReady),
+                Err(tungstenite::Error::ConnectionClosed) |
+                Err(tungstenite::Error::AlreadyClosed) => return Ok(Async::Ready(None)),
+                Err(e) => return Err(websocket_error(e)),
+            }
+        }
+    }
+}
+
+impl Sink for WebSocketTransport {
+    type SinkItem = Transmission;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: Transmission) -> StartSend<Transmission, Error> {
+        let mut buffer = BytesMut::new();
+        self.codec.write(&item, &mut buffer)?;
+        let message = match String::from_utf8(buffer.to_vec()) {
+            Ok(text) => Message::Text(text),
+            Err(e) => Message::Binary(e.into_bytes()),
+        };
+        match self.socket.write(message) {
+            Ok(()) => Ok(AsyncSink::Ready),
+            Err(ref e) if would_block(e) => Ok(AsyncSink::Ready),
+            Err(tungstenite::Error::WriteBufferFull(message)) => {
+                // Hand the transmission back until the socket drains
+                if self.poll_complete()?.is_not_ready() {
+                    return Ok(AsyncSink::NotReady(item));
+                }
+                self.write_alone(message)
+            },
+            Err(e) => Err(websocket_error(e)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        match self.socket.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if would_block(e) => Ok(Async::NotReady),
+            Err(e) => Err(websocket_error(e)),
+        }
+    }
+
+    fn close(&mut self) -> Poll<(), Error> {
+        match self.socket.close(None) {
+            Ok(()) | Err(tungstenite::Error::ConnectionClosed) => Ok(Async::Ready(())),
+            Err(ref e) if would_block(e) => Ok(Async::NotReady),
+            Err(e) => Err(websocket_error(e)),
+        }
+    }
+}
+
+#[test]
+fn parse_url_applies_default_ports() {
+    let endpoint = parse_url("ws://broker.example.com/ws").unwrap();
+    assert_eq!((endpoint.host.as_str(), endpoint.port, endpoint.secure),
+               ("broker.example.com", 80, false));
+    let endpoint = parse_url("wss://broker.example.com:15673/ws").unwrap();
+    assert_eq!((endpoint.host.as_str(), endpoint.port, endpoint.secure),
+               ("broker.example.com", 15673, true));
+    assert!(parse_url("http://broker.example.com/ws").is_err());
+}
@@ -0,0 +1,121 @@
+#![cfg(all(feature = "async", feature = "broker"))]
+extern crate futures_util;
+extern crate stomp;
+extern crate tokio;
+
+use futures_util::future::{self, FutureExt};
+use futures_util::stream::StreamExt;
+use stomp::async_session::Session;
+use stomp::broker::Broker;
+use stomp::header::{Header, SuppressedHeader};
+use std::time::Duration;
+use stomp::connection::ReconnectPolicy;
+use stomp::session::{DisconnectionReason, OutboxCapacity, PendingReceiptPolicy, ReceiptTimeout, SendBufferCapacity,
+                     SessionEvent};
+use stomp::session_builder::SessionBuilder;
+use stomp::subscription::{AckMode, AckOrNack};
+use stomp::Error;
+use tokio::runtime::{Builder, Runtime};
+
+fn runtime() -> Runtime {
+    Builder::new_current_thread().enable_all().build().unwrap()
+}
+
+fn connect(runtime: &Runtime, broker: &Broker) -> Session {
+    let mut session = runtime
+        .block_on(SessionBuilder::new("127.0.0.1", broker.port()).start_async())
+        .unwrap();
+    match runtime.block_on(session.next()) {
+        Some(SessionEvent::Connected) => session,
+        _ => panic!("expected a Connected event"),
+    }
+}
+
+#[test]
+fn messages_can_be_sent_from_many_tasks() {
+    let broker = Broker::start().unwrap();
+    let runtime = runtime();
+    let session = connect(&runtime, &broker);
+
+    let sends: Vec<_> = (0..10)
+        .map(|n| {
+            let sender = session.sender();
+            runtime.spawn(future::lazy(move |_| sender.message("/queue/async", format!("#{}", n)))
+                .then(|builder| builder.send_with_receipt()))
+        })
+        .collect();
+    for send in sends {
+        runtime.block_on(send).unwrap().unwrap();
+    }
+    assert_eq!(broker.pending("/queue/async").len(), 10);
+}
+
+#[test]
+fn subscriptions_deliver_message_events() {
+    let broker = Broker::start().unwrap();
+    let runtime = runtime();
+    let mut session = connect(&runtime, &broker);
+
+    let id = runtime.block_on(session.subscription("/queue/async").with(AckMode::Client).start()).unwrap();
+    runtime.block_on(session.message("/queue/async", "hello").send()).unwrap();
+    let frame = loop {
+        match runtime.block_on(session.next()) {
+            Some(SessionEvent::Message { destination, frame, .. }) => {
+                assert_eq!(destination, "/queue/async");
+                break frame;
+            },
+            Some(_) => {},
+            None => panic!("session ended unexpectedly"),
+        }
+    };
+    assert_eq!(frame.headers.get_subscription().unwrap().0, id);
+    assert_eq!(frame.body, b"hello".to_vec());
+    runtime.block_on(session.acknowledge_frame(&frame, AckOrNack::Ack)).unwrap();
+
+    runtime.block_on(session.disconnect()).unwrap();
+    loop {
+        match runtime.block_on(session.next()) {
+            Some(SessionEvent::Disconnected(DisconnectionReason::Requested)) => break,
+            Some(_) => {},
+            None => panic!("expected a Disconnected event"),
+        }
+    }
+    assert!(broker.unacknowledged("/queue/async").is_empty());
+}
+
+#[test]
+fn refused_connections_fail_to_start() {
+    let broker = Broker::start().unwrap();
+    let runtime = runtime();
+    let result = runtime.block_on(SessionBuilder::new("127.0.0.1", broker.port())
+        .with(SuppressedHeader("accept-version"))
+        .with(Header::new("accept-version", "9.9"))
+        .start_async());
+    match result {
+        Err(Error::Authentication(_)) => {},
+        Err(e) => panic!("expected an authentication failure, got {}", e),
+        Ok(_) => panic!("expected the broker to refuse the connection"),
+    }
+}
+
+#[test]
+fn options_the_driver_ignores_are_rejected() {
+    let broker = Broker::start().unwrap();
+    let runtime = runtime();
+    let builder = || SessionBuilder::new("127.0.0.1", broker.port());
+    let builders = vec![
+        ("ReconnectPolicy", builder().with(ReconnectPolicy::default())),
+        ("ReceiptTimeout", builder().with(ReceiptTimeout(Duration::from_secs(1)))),
+        ("PendingReceiptPolicy", builder().with(PendingReceiptPolicy::Resend)),
+        ("OutboxCapacity", builder().with(OutboxCapacity(1))),
+        ("SendBufferCapacity", builder().with(SendBufferCapacity(1))),
+    ];
+    for (option, builder) in builders {
+        match runtime.block_on(builder.start_async()) {
+            Err(Error::Transport(e)) => assert!(e.to_string().contains(option), "{}", e),
+            Err(e) => panic!("expected {} to be rejected, got {}", option, e),
+            Ok(_) => panic!("expected {} to be rejected", option),
+        }
+    }
+    assert_eq!(broker.connection_count(), 0);
+}
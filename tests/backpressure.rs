@@ -0,0 +1,89 @@
+extern crate futures;
+extern crate stomp;
+extern crate tokio_core;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+use futures::{future, Async, Future, Stream};
+use stomp::session::{SendBufferCapacity, SessionEvent};
+use stomp::session_builder::SessionBuilder;
+use tokio_core::reactor::Core;
+
+const CAPACITY: usize = 64 * 1024;
+
+#[test]
+fn sends_wait_for_a_slow_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (drain, drain_receiver) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut received = vec![];
+        let mut buffer = [0; 64 * 1024];
+        let n = socket.read(&mut buffer).unwrap();
+        received.extend_from_slice(&buffer[..n]);
+        socket.write_all(b"CONNECTED\nversion:1.2\n\n\0").unwrap();
+        // Stop reading until the client has filled its send buffer
+        drain_receiver.recv().unwrap();
+        while !received.ends_with(b"last\0") {
+            let n = socket.read(&mut buffer).unwrap();
+            assert!(n > 0, "client hung up");
+            received.extend_from_slice(&buffer[..n]);
+        }
+        let text = String::from_utf8_lossy(&received).into_owned();
+        let last = text.rsplit('\0').nth(1).unwrap();
+        let id = last.lines().find(|line| line.starts_with("receipt:")).unwrap();
+        let receipt = format!("RECEIPT\nreceipt-id:{}\n\n\0", &id["receipt:".len()..]);
+        socket.write_all(receipt.as_bytes()).unwrap();
+        text.matches("destination:/queue/big\n").count()
+    });
+
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(SendBufferCapacity(CAPACITY))
+        .start(core.handle())
+        .unwrap();
+    let body = vec![b'x'; 16 * 1024];
+    let mut sent = 0;
+    let mut saturated = false;
+    core.run(future::poll_fn(|| {
+        loop {
+            loop {
+                match session.poll() {
+                    Ok(Async::Ready(Some(SessionEvent::SendBufferFull { buffered }))) => {
+                        assert!(buffered >= CAPACITY);
+                        saturated = true;
+                    },
+                    Ok(Async::Ready(Some(_))) => {},
+                    _ => break,
+                }
+            }
+            if saturated {
+                return Ok::<_, ()>(Async::Ready(()));
+            }
+            if session.version().is_none() || session.poll_send_ready().is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+            session.message("/queue/big", &body[..]).send();
+            sent += 1;
+        }
+    })).unwrap();
+    assert!(session.buffered_bytes() >= CAPACITY);
+
+    drain.send(()).unwrap();
+    core.run(future::poll_fn(|| {
+        while let Ok(Async::Ready(Some(_))) = session.poll() {}
+        Ok::<_, ()>(session.poll_send_ready())
+    })).unwrap();
+    assert!(session.buffered_bytes() < CAPACITY);
+
+    let mut receipt = session.message("/queue/last", "last").send_with_receipt();
+    core.run(future::poll_fn(|| {
+        while let Ok(Async::Ready(Some(_))) = session.poll() {}
+        receipt.poll()
+    })).unwrap();
+    // Nothing was dropped while the connection was backed up
+    assert_eq!(server.join().unwrap(), sent);
+}
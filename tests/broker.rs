@@ -0,0 +1,310 @@
+#![cfg(feature = "broker")]
+extern crate futures;
+extern crate stomp;
+extern crate tokio_core;
+
+use futures::Stream;
+use stomp::broker::Broker;
+use stomp::frame::Frame;
+use stomp::header::{AcceptVersion, Header, StompVersion};
+use stomp::session::{GenerateReceipt, Session, SessionEvent};
+use stomp::session_builder::SessionBuilder;
+use stomp::subscription::{AckMode, AckOrNack};
+use tokio_core::reactor::Core;
+
+fn connect(core: &mut Core, broker: &Broker) -> Session {
+    let session = SessionBuilder::new("127.0.0.1", broker.port())
+        .start(core.handle())
+        .unwrap();
+    let (event, session) = next_event(core, session);
+    match event {
+        SessionEvent::Connected => session,
+        _ => panic!("expected to connect to the embedded broker"),
+    }
+}
+
+fn next_event(core: &mut Core, session: Session) -> (SessionEvent, Session) {
+    match core.run(session.into_future()) {
+        Ok((Some(event), session)) => (event, session),
+        _ => panic!("session ended unexpectedly"),
+    }
+}
+
+fn wait_for_receipt(core: &mut Core, mut session: Session) -> Session {
+    loop {
+        let (event, next) = next_event(core, session);
+        session = next;
+        if let SessionEvent::Receipt { .. } = event {
+            return session;
+        }
+    }
+}
+
+fn next_message(core: &mut Core, mut session: Session) -> (Frame, Session) {
+    loop {
+        let (event, next) = next_event(core, session);
+        session = next;
+        if let SessionEvent::Message { frame, .. } = event {
+            return (frame, session);
+        }
+    }
+}
+
+fn bodies(frames: Vec<Frame>) -> Vec<String> {
+    frames.into_iter().map(|f| String::from_utf8(f.body.to_vec()).unwrap()).collect()
+}
+
+#[test]
+fn queues_messages_until_subscribed() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, &broker);
+
+    session.message("/queue/foo", "first").send();
+    session.message("/queue/foo", "second").with(GenerateReceipt).send();
+    session = wait_for_receipt(&mut core, session);
+    assert_eq!(bodies(broker.pending("/queue/foo")), vec!["first", "second"]);
+
+    session.subscription("/queue/foo").start();
+    let (frame, session) = next_message(&mut core, session);
+    assert_eq!(frame.body, &b"first"[..]);
+    let (frame, _session) = next_message(&mut core, session);
+    assert_eq!(frame.body, &b"second"[..]);
+    assert!(broker.pending("/queue/foo").is_empty());
+    assert_eq!(broker.subscriber_count("/queue/foo"), 1);
+}
+
+#[test]
+fn client_acknowledgement_is_cumulative() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, &broker);
+
+    session.subscription("/queue/acks").with(AckMode::Client).start();
+    for body in &["a", "b", "c"] {
+        session.message("/queue/acks", *body).send();
+    }
+    let (_, session) = next_message(&mut core, session);
+    let (second, mut session) = next_message(&mut core, session);
+    session.acknowledge_frame(&second, AckOrNack::Ack).unwrap();
+    session.message("/queue/sync", "").with(GenerateReceipt).send();
+    let _session = wait_for_receipt(&mut core, session);
+    assert_eq!(bodies(broker.unacknowledged("/queue/acks")), vec!["c"]);
+}
+
+#[test]
+fn acknowledgements_can_join_a_transaction() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, &broker);
+
+    session.subscription("/queue/tx-acks").with(AckMode::ClientIndividual).start();
+    session.message("/queue/tx-acks", "a").send();
+    let (frame, mut session) = next_message(&mut core, session);
+    {
+        let mut transaction = session.begin_transaction();
+        transaction.acknowledge_frame(&frame, AckOrNack::Ack).unwrap();
+        transaction.abort();
+    }
+    session.message("/queue/sync", "").with(GenerateReceipt).send();
+    let mut session = wait_for_receipt(&mut core, session);
+    assert_eq!(bodies(broker.unacknowledged("/queue/tx-acks")), vec!["a"]);
+
+    {
+        let mut transaction = session.begin_transaction();
+        transaction.acknowledge_frame(&frame, AckOrNack::Ack).unwrap();
+        transaction.commit();
+    }
+    session.message("/queue/sync", "").with(GenerateReceipt).send();
+    let mut session = wait_for_receipt(&mut core, session);
+    assert!(broker.unacknowledged("/queue/tx-acks").is_empty());
+
+    match session.acknowledge_frame(&Frame::send("/queue/tx-acks", b""), AckOrNack::Ack) {
+        Err(stomp::Error::Protocol { .. }) => {},
+        _ => panic!("expected a frame without an ack header to be rejected"),
+    }
+}
+
+#[test]
+fn individual_acknowledgements_can_be_sent_up_to_a_message() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, &broker);
+
+    let sub_id = session.subscription("/queue/up-to").with(AckMode::ClientIndividual).start();
+    for body in &["a", "b", "c"] {
+        session.message("/queue/up-to", *body).send();
+    }
+    let (_, session) = next_message(&mut core, session);
+    let (second, session) = next_message(&mut core, session);
+    let (_, mut session) = next_message(&mut core, session);
+    assert_eq!(session.unacked_count(&sub_id), 3);
+
+    session.ack_up_to(&second).unwrap();
+    assert_eq!(session.unacked_count(&sub_id), 1);
+    session.message("/queue/sync", "").with(GenerateReceipt).send();
+    let mut session = wait_for_receipt(&mut core, session);
+    assert_eq!(bodies(broker.unacknowledged("/queue/up-to")), vec!["c"]);
+
+    session.unsubscribe(&sub_id);
+    assert_eq!(session.unacked_count(&sub_id), 0);
+    session.message("/queue/sync", "").with(GenerateReceipt).send();
+    let _session = wait_for_receipt(&mut core, session);
+    assert!(broker.unacknowledged("/queue/up-to").is_empty());
+    assert_eq!(bodies(broker.pending("/queue/up-to")), vec!["c"]);
+}
+
+#[test]
+fn transactions_are_applied_on_commit() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, &broker);
+
+    {
+        let mut transaction = session.begin_transaction();
+        transaction.message("/queue/tx", "Animal").send();
+        transaction.message("/queue/tx", "Vegetable").send();
+        transaction.abort();
+    }
+    {
+        let mut transaction = session.begin_transaction();
+        transaction.message("/queue/tx", "Mineral").send();
+        transaction.commit();
+    }
+    session.message("/queue/sync", "").with(GenerateReceipt).send();
+    let _session = wait_for_receipt(&mut core, session);
+    assert_eq!(bodies(broker.pending("/queue/tx")), vec!["Mineral"]);
+}
+
+#[test]
+fn disconnecting_requeues_unacknowledged_messages() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, &broker);
+
+    session.subscription("/queue/requeue").with(AckMode::ClientIndividual).start();
+    session.message("/queue/requeue", "unlucky").send();
+    let (_, mut session) = next_message(&mut core, session);
+    assert_eq!(broker.unacknowledged("/queue/requeue").len(), 1);
+
+    session.disconnect();
+    loop {
+        let (event, next) = next_event(&mut core, session);
+        session = next;
+        if let SessionEvent::Disconnected(_) = event {
+            break;
+        }
+    }
+    while broker.connection_count() > 0 {
+        ::std::thread::yield_now();
+    }
+    assert_eq!(bodies(broker.pending("/queue/requeue")), vec!["unlucky"]);
+}
+
+#[test]
+fn headers_are_escaped_for_the_negotiated_version() {
+    let cases = [(StompVersion::Stomp_v1_0, "a:b\\c"),
+                 (StompVersion::Stomp_v1_1, "a:b\\c\rd"),
+                 (StompVersion::Stomp_v1_2, "a:b\\c\r\nd")];
+    for &(version, value) in cases.iter() {
+        let broker = Broker::start().unwrap();
+        let mut core = Core::new().unwrap();
+        let session = SessionBuilder::new("127.0.0.1", broker.port())
+            .with(AcceptVersion(vec![version]))
+            .start(core.handle())
+            .unwrap();
+        let (event, mut session) = next_event(&mut core, session);
+        assert!(matches!(event, SessionEvent::Connected));
+        assert_eq!(session.version(), Some(version));
+
+        session.message("/queue/escapes", "")
+            .with(Header::new("note", value))
+            .with(GenerateReceipt)
+            .send();
+        let _session = wait_for_receipt(&mut core, session);
+        let pending = broker.pending("/queue/escapes");
+        assert_eq!(pending[0].headers.get_header("note").unwrap().get_value(), value);
+    }
+}
+
+#[test]
+fn heart_beats_keep_the_connection_alive() {
+    use futures::Future;
+    use std::time::Duration;
+    use stomp::broker::BrokerConfig;
+    use stomp::connection::HeartBeat;
+    use tokio_core::reactor::Timeout;
+
+    let broker = Broker::start_with_config(BrokerConfig { heartbeat: HeartBeat(100, 100) }).unwrap();
+    let mut core = Core::new().unwrap();
+    let session = SessionBuilder::new("127.0.0.1", broker.port())
+        .with(HeartBeat(100, 100))
+        .start(core.handle())
+        .unwrap();
+    let (_, session) = next_event(&mut core, session);
+    let timeout = Timeout::new(Duration::from_millis(800), &core.handle()).unwrap();
+    // keep the session alive until the broker has been checked
+    let _session = match core.run(session.into_future().select2(timeout)) {
+        Ok(futures::future::Either::B((_, session))) => session,
+        _ => panic!("expected the session to stay quiet and connected"),
+    };
+    assert_eq!(broker.connection_count(), 1);
+}
+
+#[test]
+fn refused_connections_report_the_error_frame() {
+    use stomp::header::{Header, SuppressedHeader};
+    use stomp::session::DisconnectionReason;
+    use stomp::Error;
+
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let session = SessionBuilder::new("127.0.0.1", broker.port())
+        .with(SuppressedHeader("accept-version"))
+        .with(Header::new("accept-version", "9.9"))
+        .start(core.handle())
+        .unwrap();
+    let (event, session) = next_event(&mut core, session);
+    match event {
+        SessionEvent::ErrorFrame(_) => {},
+        _ => panic!("expected the ERROR frame to be reported"),
+    }
+    let (event, _session) = next_event(&mut core, session);
+    match event {
+        SessionEvent::Disconnected(DisconnectionReason::ConnectFailed(Error::Authentication(frame))) => {
+            assert!(frame.headers.get_header("message").is_some());
+        },
+        _ => panic!("expected the connection to be refused"),
+    }
+}
+
+#[test]
+fn oversized_frames_disconnect_the_session() {
+    use stomp::codec::MaxBodyLength;
+    use stomp::session::DisconnectionReason;
+
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut publisher = connect(&mut core, &broker);
+    publisher.message("/queue/big", vec![b'x'; 64]).with(GenerateReceipt).send();
+    let _publisher = wait_for_receipt(&mut core, publisher);
+
+    let session = SessionBuilder::new("127.0.0.1", broker.port())
+        .with(MaxBodyLength(16))
+        .start(core.handle())
+        .unwrap();
+    let (_, mut session) = next_event(&mut core, session);
+    session.subscription("/queue/big").start();
+    loop {
+        let (event, next) = next_event(&mut core, session);
+        session = next;
+        match event {
+            SessionEvent::Disconnected(DisconnectionReason::FrameTooLarge(message)) => {
+                assert!(message.contains("16"));
+                break;
+            },
+            SessionEvent::Message { .. } => panic!("the oversized message was delivered"),
+            _ => {},
+        }
+    }
+}
@@ -0,0 +1,144 @@
+extern crate bytes;
+extern crate proptest;
+extern crate stomp;
+extern crate tokio_io;
+
+use bytes::BytesMut;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use stomp::codec::{Codec, ServerCodec};
+use stomp::frame::{Command, Frame, Transmission};
+use stomp::header::{Header, HeaderList};
+use tokio_io::codec::Decoder;
+
+// Mostly the characters that need escaping, plus anything else a String can hold
+fn header_text() -> BoxedStrategy<String> {
+    let special = prop::sample::select(vec![':', '\\', '\n', '\r', ' ', '\0']);
+    vec(prop_oneof![special, any::<char>()], 1..16)
+        .prop_map(|chars| chars.into_iter().collect())
+        .boxed()
+}
+
+// Bodies that contain NUL must announce their length; the rest may or may not
+fn frame(commands: Vec<Command>) -> BoxedStrategy<Frame> {
+    let body = prop_oneof![vec(any::<u8>(), 0..256), vec(prop::sample::select(vec![0u8, b'\n', b'a']), 0..16)];
+    (prop::sample::select(commands),
+     vec((header_text(), header_text()), 0..6),
+     body,
+     any::<bool>())
+        .prop_map(|(command, headers, body, with_length)| {
+            let mut list = HeaderList::new();
+            for (key, value) in headers {
+                // A stray content-length would change how the body is read
+                if key != "content-length" {
+                    list.push(Header::new(&key, &value));
+                }
+            }
+            if with_length || body.contains(&0) {
+                list.push(Header::new("content-length", &body.len().to_string()));
+            }
+            Frame {
+                command,
+                headers: list,
+                body: body.into(),
+            }
+        })
+        .boxed()
+}
+
+// CONNECTED, CONNECT and STOMP are left out because their headers are never escaped
+fn server_frame() -> BoxedStrategy<Frame> {
+    frame(vec![Command::Message, Command::Receipt, Command::Error])
+}
+
+fn client_frame() -> BoxedStrategy<Frame> {
+    frame(vec![Command::Send, Command::Subscribe, Command::Unsubscribe, Command::Begin,
+               Command::Commit, Command::Abort, Command::Ack, Command::Nack, Command::Disconnect])
+}
+
+fn summary(frame: &Frame) -> (String, Vec<(String, String)>, Vec<u8>) {
+    let headers = frame.headers.iter().map(|h| (h.0.clone(), h.1.clone())).collect();
+    (frame.command.to_string(), headers, frame.body.to_vec())
+}
+
+fn encode(frames: &[Frame]) -> Vec<u8> {
+    let mut buffer = BytesMut::new();
+    for frame in frames {
+        frame.write(&mut buffer);
+    }
+    buffer.to_vec()
+}
+
+// Feeds `bytes` to the codec in the given chunks, collecting every frame it decodes
+fn decode_chunks<D>(codec: &mut D, bytes: &[u8], chunks: &[usize]) -> Vec<Frame>
+    where D: Decoder<Item = Transmission, Error = stomp::Error>
+{
+    let mut buffer = BytesMut::new();
+    let mut frames = vec![];
+    let mut rest = bytes;
+    let mut chunks = chunks.iter().cloned().chain(::std::iter::repeat(1));
+    while !rest.is_empty() {
+        let length = chunks.next().unwrap().max(1).min(rest.len());
+        buffer.extend(&rest[..length]);
+        rest = &rest[length..];
+        while let Some(transmission) = codec.decode(&mut buffer).unwrap() {
+            if let Transmission::CompleteFrame(frame) = transmission {
+                frames.push(frame);
+            }
+        }
+    }
+    assert!(buffer.is_empty(), "{} byte(s) left undecoded", buffer.len());
+    frames
+}
+
+proptest! {
+    #[test]
+    fn server_frames_round_trip(frames in vec(server_frame(), 1..4),
+                                chunks in vec(1usize..64, 0..32)) {
+        let decoded = decode_chunks(&mut Codec::default(), &encode(&frames), &chunks);
+        prop_assert_eq!(decoded.iter().map(summary).collect::<Vec<_>>(),
+                        frames.iter().map(summary).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn client_frames_round_trip(frames in vec(client_frame(), 1..4),
+                                chunks in vec(1usize..64, 0..32)) {
+        let decoded = decode_chunks(&mut ServerCodec::default(), &encode(&frames), &chunks);
+        prop_assert_eq!(decoded.iter().map(summary).collect::<Vec<_>>(),
+                        frames.iter().map(summary).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn frames_survive_a_split_at_every_byte(frame in server_frame()) {
+        let bytes = encode(&[frame.clone()]);
+        for split in 1..bytes.len() {
+            let decoded = decode_chunks(&mut Codec::default(), &bytes, &[split, bytes.len()]);
+            prop_assert_eq!(decoded.len(), 1);
+            prop_assert_eq!(summary(&decoded[0]), summary(&frame));
+        }
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic(bytes in vec(any::<u8>(), 0..512),
+                                   chunks in vec(1usize..64, 0..16)) {
+        let mut codec = Codec::default();
+        let mut buffer = BytesMut::new();
+        let mut rest = &bytes[..];
+        for &chunk in chunks.iter().chain(::std::iter::repeat(&64)) {
+            if rest.is_empty() {
+                break;
+            }
+            let length = chunk.min(rest.len());
+            buffer.extend(&rest[..length]);
+            rest = &rest[length..];
+            // After an error the connection would be closed, so the rest is not decoded
+            loop {
+                match codec.decode(&mut buffer) {
+                    Ok(Some(_)) => {},
+                    Ok(None) => break,
+                    Err(_) => return Ok(()),
+                }
+            }
+        }
+    }
+}
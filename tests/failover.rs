@@ -0,0 +1,80 @@
+#![cfg(feature = "broker")]
+extern crate futures;
+extern crate stomp;
+extern crate tokio_core;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use futures::Stream;
+use stomp::broker::Broker;
+use stomp::connection::{Endpoint, Failover, ReconnectPolicy};
+use stomp::session::{Session, SessionEvent};
+use stomp::session_builder::SessionBuilder;
+use tokio_core::reactor::Core;
+
+// A port that refuses connections
+fn closed_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn next_event(core: &mut Core, session: Session) -> (SessionEvent, Session) {
+    match core.run(session.into_future()) {
+        Ok((Some(event), session)) => (event, session),
+        _ => panic!("session ended unexpectedly"),
+    }
+}
+
+#[test]
+fn unreachable_brokers_are_skipped() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", closed_port())
+        .with(Failover::new(vec![Endpoint::new("127.0.0.1", broker.port())]))
+        .start(core.handle())
+        .unwrap();
+    loop {
+        let (event, next) = next_event(&mut core, session);
+        session = next;
+        if let SessionEvent::Connected = event {
+            break;
+        }
+    }
+    assert_eq!(*session.endpoint(), Endpoint::new("127.0.0.1", broker.port()));
+    assert_eq!(broker.connection_count(), 1);
+}
+
+#[test]
+fn reconnecting_moves_on_to_the_next_broker() {
+    let broker = Broker::start().unwrap();
+    let primary = TcpListener::bind("127.0.0.1:0").unwrap();
+    let primary_port = primary.local_addr().unwrap().port();
+    // Accepts the session, then goes away
+    let server = thread::spawn(move || {
+        let (mut socket, _) = primary.accept().unwrap();
+        let mut buffer = [0; 1024];
+        socket.read(&mut buffer).unwrap();
+        socket.write_all(b"CONNECTED\nversion:1.2\n\n\0").unwrap();
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", primary_port)
+        .with(Failover::new(vec![Endpoint::new("127.0.0.1", broker.port())]))
+        .with(ReconnectPolicy::exponential(10, 10))
+        .start(core.handle())
+        .unwrap();
+    let (event, next) = next_event(&mut core, session);
+    session = next;
+    assert!(matches!(event, SessionEvent::Connected));
+    assert_eq!(*session.endpoint(), Endpoint::new("127.0.0.1", primary_port));
+    server.join().unwrap();
+
+    loop {
+        let (event, next) = next_event(&mut core, session);
+        session = next;
+        if let SessionEvent::Reconnected { .. } = event {
+            break;
+        }
+    }
+    assert_eq!(*session.endpoint(), Endpoint::new("127.0.0.1", broker.port()));
+    assert_eq!(broker.connection_count(), 1);
+}
@@ -0,0 +1,419 @@
+#![cfg(feature = "broker")]
+extern crate futures;
+extern crate stomp;
+extern crate tokio_core;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use futures::{future, Async, Future, Stream};
+use stomp::broker::Broker;
+use stomp::connection::ReconnectPolicy;
+use stomp::header::Header;
+use stomp::session::{DisconnectionReason, GenerateReceipt, OutboxCapacity, PendingReceiptPolicy, PublisherStats, ReceiptTimeout, Session, SessionEvent};
+use stomp::session_builder::SessionBuilder;
+use stomp::Error;
+use tokio_core::reactor::Core;
+
+fn connect(core: &mut Core, builder: SessionBuilder) -> Session {
+    let session = builder.start(core.handle()).unwrap();
+    match core.run(session.into_future()) {
+        Ok((Some(SessionEvent::Connected), session)) => session,
+        _ => panic!("expected to connect to the embedded broker"),
+    }
+}
+
+// Drives the session while waiting for `f` to complete
+fn wait<F: Future>(core: &mut Core, session: &mut Session, mut f: F) -> Result<F::Item, F::Error> {
+    core.run(future::poll_fn(|| {
+        while let Ok(Async::Ready(Some(_))) = session.poll() {}
+        f.poll()
+    }))
+}
+
+#[test]
+fn send_with_receipt_resolves_on_receipt() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, SessionBuilder::new("127.0.0.1", broker.port()));
+
+    let receipt = session.message("/queue/receipts", "hello").send_with_receipt();
+    let id = receipt.id().to_owned();
+    let frame = wait(&mut core, &mut session, receipt).unwrap();
+    assert_eq!(frame.headers.get_receipt_id().unwrap().0, id);
+    assert_eq!(broker.pending("/queue/receipts").len(), 1);
+
+    let (_, subscribed) = session.subscription("/queue/other").start_with_receipt();
+    wait(&mut core, &mut session, subscribed).unwrap();
+    assert_eq!(broker.subscriber_count("/queue/other"), 1);
+
+    let mut transaction = session.begin_transaction();
+    transaction.message("/queue/receipts", "in a transaction").send();
+    let committed = transaction.commit_with_receipt();
+    wait(&mut core, &mut session, committed).unwrap();
+    assert_eq!(broker.pending("/queue/receipts").len(), 2);
+}
+
+#[test]
+fn error_frames_fail_the_matching_receipt() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, SessionBuilder::new("127.0.0.1", broker.port()));
+
+    let receipt = session.message("/queue/receipts", "hello")
+        .with(Header::new("transaction", "no-such-transaction"))
+        .send_with_receipt();
+    let id = receipt.id().to_owned();
+    match wait(&mut core, &mut session, receipt) {
+        Err(Error::Broker(error)) => {
+            assert!(error.message.is_some());
+            assert_eq!(error.receipt_id.as_ref(), Some(&id));
+        },
+        _ => panic!("expected the receipt to fail with the broker's ERROR frame"),
+    }
+}
+
+#[test]
+fn error_frames_disconnect_the_session() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, SessionBuilder::new("127.0.0.1", broker.port()));
+
+    let _receipt = session.message("/queue/receipts", "hello")
+        .with(Header::new("transaction", "no-such-transaction"))
+        .send_with_receipt();
+    let reason = core.run(future::poll_fn(|| {
+        loop {
+            match session.poll() {
+                Ok(Async::Ready(Some(SessionEvent::Disconnected(reason)))) => return Ok(Async::Ready(reason)),
+                Ok(Async::Ready(Some(_))) => {},
+                Ok(Async::Ready(None)) | Err(_) => return Err(()),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+            }
+        }
+    })).unwrap();
+    match reason {
+        DisconnectionReason::BrokerError(error) => {
+            let original = error.original.expect("the offending SEND to be attached");
+            assert_eq!(original.headers.get_header("transaction").unwrap().get_value(), "no-such-transaction");
+            assert!(error.message.is_some());
+        },
+        _ => panic!("expected the session to disconnect with the broker's error"),
+    }
+}
+
+// Accepts a client, replies CONNECTED and returns the first frame it sends afterwards
+fn accept_and_read_frame(listener: &TcpListener) -> (TcpStream, String) {
+    let (mut socket, _) = listener.accept().unwrap();
+    let mut received = vec![];
+    let mut buffer = [0; 1024];
+    let mut frames = 0;
+    while frames < 2 {
+        let n = socket.read(&mut buffer).unwrap();
+        assert!(n > 0, "client hung up");
+        if frames == 0 && received.is_empty() {
+            socket.write_all(b"CONNECTED\nversion:1.2\n\n\0").unwrap();
+        }
+        received.extend_from_slice(&buffer[..n]);
+        frames = received.iter().filter(|&&b| b == 0).count();
+    }
+    let text = String::from_utf8(received).unwrap();
+    let frame = text.split('\0').nth(1).unwrap().trim_start_matches('\n').to_owned();
+    (socket, frame)
+}
+
+fn receipt_header(frame: &str) -> String {
+    frame.lines()
+        .find(|line| line.starts_with("receipt:"))
+        .map(|line| line["receipt:".len()..].to_owned())
+        .unwrap()
+}
+
+#[test]
+fn pending_receipts_fail_when_the_connection_drops() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (socket, frame) = accept_and_read_frame(&listener);
+        assert!(frame.starts_with("SEND"));
+        drop(socket);
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, SessionBuilder::new("127.0.0.1", port));
+
+    let receipt = session.message("/queue/receipts", "hello").send_with_receipt();
+    match wait(&mut core, &mut session, receipt) {
+        Err(Error::SessionClosed) => {},
+        _ => panic!("expected the receipt to fail once the connection dropped"),
+    }
+    server.join().unwrap();
+}
+
+#[test]
+fn pending_receipts_are_resent_after_reconnecting() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (socket, first) = accept_and_read_frame(&listener);
+        drop(socket);
+        let (mut socket, second) = accept_and_read_frame(&listener);
+        assert_eq!(first, second);
+        let receipt = format!("RECEIPT\nreceipt-id:{}\n\n\0", receipt_header(&second));
+        socket.write_all(receipt.as_bytes()).unwrap();
+        socket
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, SessionBuilder::new("127.0.0.1", port)
+        .with(ReconnectPolicy::exponential(10, 10))
+        .with(PendingReceiptPolicy::Resend));
+
+    let receipt = session.message("/queue/receipts", "hello").send_with_receipt();
+    let id = receipt.id().to_owned();
+    let frame = wait(&mut core, &mut session, receipt).unwrap();
+    assert_eq!(frame.headers.get_receipt_id().unwrap().0, id);
+    server.join().unwrap();
+}
+
+#[test]
+fn disconnect_receipts_are_not_resent_after_reconnecting() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (socket, first) = accept_and_read_frame(&listener);
+        assert!(first.starts_with("DISCONNECT"));
+        drop(socket);
+        let (mut socket, second) = accept_and_read_frame(&listener);
+        let receipt = format!("RECEIPT\nreceipt-id:{}\n\n\0", receipt_header(&second));
+        socket.write_all(receipt.as_bytes()).unwrap();
+        (socket, second)
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, SessionBuilder::new("127.0.0.1", port)
+        .with(ReconnectPolicy::exponential(10, 10))
+        .with(PendingReceiptPolicy::Resend));
+
+    let disconnected = session.disconnect_with_receipt();
+    match wait(&mut core, &mut session, disconnected) {
+        Err(Error::SessionClosed) => {},
+        _ => panic!("expected the DISCONNECT receipt to fail with the connection"),
+    }
+    let receipt = session.message("/queue/receipts", "hello").send_with_receipt();
+    wait(&mut core, &mut session, receipt).unwrap();
+    let (_socket, second) = server.join().unwrap();
+    assert!(second.starts_with("SEND"), "expected no DISCONNECT on the new connection:\n{}", second);
+}
+
+#[test]
+fn expired_receipts_are_reported_as_events() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(ReceiptTimeout(Duration::from_millis(50)))
+        .start(core.handle())
+        .unwrap();
+
+    let id = session.message("/queue/receipts", "hello").send_with_receipt().id().to_owned();
+    loop {
+        match core.run(session.into_future()) {
+            Ok((Some(SessionEvent::ReceiptTimeout { id: expired, original }), _)) => {
+                assert_eq!(expired, id);
+                assert_eq!(original.body, b"hello".to_vec());
+                break;
+            },
+            Ok((Some(_), next)) => session = next,
+            _ => panic!("session ended before the receipt expired"),
+        }
+    }
+    drop(listener);
+}
+
+#[test]
+fn receipts_time_out() {
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(ReceiptTimeout(Duration::from_millis(50)))
+        .start(core.handle())
+        .unwrap();
+
+    let receipt = session.message("/queue/receipts", "hello").send_with_receipt();
+    match wait(&mut core, &mut session, receipt) {
+        Err(Error::ReceiptTimeout(_)) => {},
+        _ => panic!("expected the receipt to time out"),
+    }
+}
+
+// Drives the session until `done` holds
+fn drive_until<P: FnMut(&Session) -> bool>(core: &mut Core, session: &mut Session, mut done: P) {
+    core.run(future::poll_fn(|| {
+        while let Ok(Async::Ready(Some(_))) = session.poll() {}
+        Ok::<_, ()>(if done(session) { Async::Ready(()) } else { Async::NotReady })
+    })).unwrap()
+}
+
+#[test]
+fn reliable_messages_are_confirmed() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, SessionBuilder::new("127.0.0.1", broker.port()));
+
+    for body in &["one", "two", "three"] {
+        session.message("/queue/reliable", *body).send_reliably().unwrap();
+    }
+    assert_eq!(session.publisher_stats().in_flight, 3);
+    drive_until(&mut core, &mut session, |session| session.publisher_stats().in_flight == 0);
+    assert_eq!(session.publisher_stats(), PublisherStats { in_flight: 0, confirmed: 3, retried: 0 });
+    assert_eq!(broker.pending("/queue/reliable").len(), 3);
+}
+
+#[test]
+fn a_full_outbox_refuses_messages() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(OutboxCapacity(2))
+        .start(core.handle())
+        .unwrap();
+
+    session.message("/queue/reliable", "one").send_reliably().unwrap();
+    session.message("/queue/reliable", "two").send_reliably().unwrap();
+    match session.message("/queue/reliable", "three").send_reliably() {
+        Err(Error::OutboxFull) => {},
+        _ => panic!("expected the outbox to be full"),
+    }
+    let ready = core.run(future::lazy(|| Ok::<_, ()>(session.poll_outbox()))).unwrap();
+    assert!(ready.is_not_ready());
+    assert_eq!(session.publisher_stats().in_flight, 2);
+    drop(listener);
+}
+
+#[test]
+fn reliable_messages_are_retried_after_reconnecting() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (socket, _) = accept_and_read_frame(&listener);
+        drop(socket);
+        let (mut socket, frame) = accept_and_read_frame(&listener);
+        let receipt = format!("RECEIPT\nreceipt-id:{}\n\n\0", receipt_header(&frame));
+        socket.write_all(receipt.as_bytes()).unwrap();
+        socket
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, SessionBuilder::new("127.0.0.1", port)
+        .with(ReconnectPolicy::exponential(10, 10)));
+
+    session.message("/queue/reliable", "hello").send_reliably().unwrap();
+    drive_until(&mut core, &mut session, |session| session.publisher_stats().confirmed == 1);
+    assert_eq!(session.publisher_stats(), PublisherStats { in_flight: 0, confirmed: 1, retried: 1 });
+    server.join().unwrap();
+}
+
+#[test]
+fn shutdown_waits_for_receipts_and_unsubscribes() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, SessionBuilder::new("127.0.0.1", broker.port()));
+
+    let (_, subscribed) = session.subscription("/queue/shutdown").start_with_receipt();
+    wait(&mut core, &mut session, subscribed).unwrap();
+    assert_eq!(broker.subscriber_count("/queue/shutdown"), 1);
+    session.message("/queue/other", "hello").send_reliably().unwrap();
+
+    let shutdown = session.shutdown(Duration::from_secs(5));
+    match session.message("/queue/other", "too late").send_reliably() {
+        Err(Error::SessionClosed) => {},
+        _ => panic!("expected sends to be refused while shutting down"),
+    }
+    wait(&mut core, &mut session, shutdown).unwrap();
+    assert_eq!(session.publisher_stats().confirmed, 1);
+    assert_eq!(broker.subscriber_count("/queue/shutdown"), 0);
+    let pending = broker.pending("/queue/other");
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].body, &b"hello"[..]);
+}
+
+#[test]
+fn receipts_are_refused_while_shutting_down() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, SessionBuilder::new("127.0.0.1", broker.port()));
+    let pending = session.message("/queue/other", "before").send_with_receipt();
+
+    let shutdown = session.shutdown(Duration::from_secs(5));
+    let refused = vec![
+        session.message("/queue/other", "too late").send_with_receipt(),
+        session.subscription("/queue/shutdown").start_with_receipt().1,
+        session.begin_transaction().commit_with_receipt(),
+        session.disconnect_with_receipt(),
+    ];
+    session.message("/queue/other", "too late").with(GenerateReceipt).send();
+    for mut receipt in refused {
+        match core.run(future::lazy(|| Ok::<_, ()>(receipt.poll()))).unwrap() {
+            Err(Error::SessionClosed) => {},
+            _ => panic!("expected {} to fail straight away", receipt.id()),
+        }
+    }
+    // Nothing is left for the shutdown to wait for but the first receipt
+    wait(&mut core, &mut session, pending.join(shutdown)).unwrap();
+    assert_eq!(broker.pending("/queue/other").len(), 1);
+}
+
+#[test]
+fn shutdown_gives_up_on_a_missing_disconnect_receipt() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (socket, frame) = accept_and_read_frame(&listener);
+        assert!(frame.starts_with("DISCONNECT"));
+        (socket, receipt_header(&frame))
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, SessionBuilder::new("127.0.0.1", port));
+
+    let shutdown = session.shutdown(Duration::from_millis(100));
+    let (_socket, id) = server.join().unwrap();
+    assert!(id.starts_with("disconnect/"));
+    match wait(&mut core, &mut session, shutdown) {
+        Err(Error::ReceiptTimeout(expired)) => assert_eq!(expired, id),
+        _ => panic!("expected the shutdown to time out"),
+    }
+}
+
+#[test]
+fn shutdown_gives_up_on_the_outbox_after_the_receipt_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (mut socket, frame) = accept_and_read_frame(&listener);
+        assert!(frame.starts_with("SEND"));
+        // Never confirm the message, only the DISCONNECT that follows it
+        let mut received = vec![];
+        let mut buffer = [0; 1024];
+        let disconnect = loop {
+            let n = socket.read(&mut buffer).unwrap();
+            assert!(n > 0, "client hung up");
+            received.extend_from_slice(&buffer[..n]);
+            let text = String::from_utf8_lossy(&received).into_owned();
+            if let Some(frame) = text.split('\0').find(|frame| frame.trim_start_matches('\n').starts_with("DISCONNECT")) {
+                break frame.to_owned();
+            }
+        };
+        let receipt = format!("RECEIPT\nreceipt-id:{}\n\n\0", receipt_header(&disconnect));
+        socket.write_all(receipt.as_bytes()).unwrap();
+        socket
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, SessionBuilder::new("127.0.0.1", port)
+        .with(ReceiptTimeout(Duration::from_millis(100))));
+
+    session.message("/queue/reliable", "unconfirmed").send_reliably().unwrap();
+    let shutdown = session.shutdown(Duration::from_secs(10));
+    wait(&mut core, &mut session, shutdown).unwrap();
+    assert_eq!(session.publisher_stats().in_flight, 0);
+    server.join().unwrap();
+}
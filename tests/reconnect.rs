@@ -0,0 +1,173 @@
+#![cfg(feature = "broker")]
+extern crate futures;
+extern crate stomp;
+extern crate tokio_core;
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use futures::{future, Async, Future, Stream};
+use stomp::broker::Broker;
+use stomp::connection::ReconnectPolicy;
+use stomp::header::Header;
+use stomp::session::{DisconnectionReason, GenerateReceipt, Session, SessionEvent};
+use stomp::session_builder::SessionBuilder;
+use stomp::subscription::AckMode;
+use tokio_core::reactor::{Core, Timeout};
+
+fn next_event(core: &mut Core, session: Session) -> (SessionEvent, Session) {
+    match core.run(session.into_future()) {
+        Ok((Some(event), session)) => (event, session),
+        _ => panic!("session ended unexpectedly"),
+    }
+}
+
+fn copy(mut from: TcpStream, mut to: TcpStream, captured: Option<Arc<Mutex<Vec<u8>>>>) {
+    let mut buffer = [0; 4096];
+    loop {
+        let n = from.read(&mut buffer).unwrap_or(0);
+        if n == 0 || to.write_all(&buffer[..n]).is_err() {
+            let _ = to.shutdown(Shutdown::Both);
+            return;
+        }
+        if let Some(ref captured) = captured {
+            captured.lock().unwrap().extend_from_slice(&buffer[..n]);
+        }
+    }
+}
+
+// Forwards connections to the broker, recording what clients send and handing
+// back each client socket so the test can cut it
+fn proxy(broker_port: u16) -> io::Result<(u16, mpsc::Receiver<(TcpStream, Arc<Mutex<Vec<u8>>>)>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    let (connections, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let client = client.unwrap();
+            let upstream = TcpStream::connect(("127.0.0.1", broker_port)).unwrap();
+            let captured = Arc::new(Mutex::new(vec![]));
+            let (client_in, upstream_out) = (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            let client_out = client.try_clone().unwrap();
+            let recorder = captured.clone();
+            thread::spawn(move || copy(client_in, upstream_out, Some(recorder)));
+            thread::spawn(move || copy(upstream, client_out, None));
+            if connections.send((client, captured)).is_err() {
+                return;
+            }
+        }
+    });
+    Ok((port, receiver))
+}
+
+#[test]
+fn subscriptions_are_replayed_after_reconnecting() {
+    let broker = Broker::start().unwrap();
+    let (port, connections) = proxy(broker.port()).unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(ReconnectPolicy::exponential(10, 10))
+        .start(core.handle())
+        .unwrap();
+    let (event, next) = next_event(&mut core, session);
+    session = next;
+    assert!(matches!(event, SessionEvent::Connected));
+
+    session.subscription("/queue/replayed")
+        .with(AckMode::Client)
+        .with(Header::new("x-custom", "kept"))
+        .with(GenerateReceipt)
+        .start();
+    loop {
+        let (event, next) = next_event(&mut core, session);
+        session = next;
+        if let SessionEvent::Receipt { .. } = event {
+            break;
+        }
+    }
+    assert_eq!(broker.subscriber_count("/queue/replayed"), 1);
+
+    let (first, _) = connections.recv().unwrap();
+    first.shutdown(Shutdown::Both).unwrap();
+    let mut reconnecting = false;
+    loop {
+        let (event, next) = next_event(&mut core, session);
+        session = next;
+        match event {
+            SessionEvent::Reconnecting { .. } => reconnecting = true,
+            SessionEvent::Reconnected { .. } => break,
+            _ => {},
+        }
+    }
+    assert!(reconnecting, "expected Reconnecting before Reconnected");
+
+    // The broker handles the replayed SUBSCRIBE before it acknowledges this SEND
+    session.message("/queue/replayed", "after").with(GenerateReceipt).send();
+    let (mut delivered, mut received) = (None, false);
+    while delivered.is_none() || !received {
+        let (event, next) = next_event(&mut core, session);
+        session = next;
+        match event {
+            SessionEvent::Message { frame, ack_mode, .. } => {
+                assert!(matches!(ack_mode, AckMode::Client));
+                delivered = Some(frame);
+            },
+            SessionEvent::Receipt { .. } => received = true,
+            _ => {},
+        }
+    }
+    assert_eq!(delivered.unwrap().body, &b"after"[..]);
+    assert_eq!(broker.subscriber_count("/queue/replayed"), 1);
+    assert_eq!(broker.unacknowledged("/queue/replayed").len(), 1);
+
+    let (_, captured) = connections.recv().unwrap();
+    let sent = String::from_utf8(captured.lock().unwrap().clone()).unwrap();
+    let subscribe = sent.split('\0')
+        .map(|frame| frame.trim_start_matches('\n'))
+        .find(|frame| frame.starts_with("SUBSCRIBE"))
+        .expect("the subscription to be replayed");
+    assert!(subscribe.contains("\nx-custom:kept\n"), "{}", subscribe);
+    assert!(subscribe.contains("\nack:client\n"), "{}", subscribe);
+}
+
+// Sends a SEND the broker answers with an ERROR, then collects the session's events for a while
+fn events_after_a_broker_error(policy: ReconnectPolicy) -> Vec<SessionEvent> {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", broker.port())
+        .with(policy)
+        .start(core.handle())
+        .unwrap();
+    let (event, next) = next_event(&mut core, session);
+    session = next;
+    assert!(matches!(event, SessionEvent::Connected));
+
+    session.message("/queue/refused", "hello")
+        .with(Header::new("transaction", "no-such-transaction"))
+        .send();
+    let mut events = vec![];
+    let mut timeout = Timeout::new(Duration::from_millis(200), &core.handle()).unwrap();
+    core.run(future::poll_fn(|| {
+        while let Ok(Async::Ready(Some(event))) = session.poll() {
+            events.push(event);
+        }
+        timeout.poll()
+    })).unwrap();
+    events
+}
+
+#[test]
+fn broker_errors_end_the_session() {
+    let events = events_after_a_broker_error(ReconnectPolicy::exponential(10, 10));
+    assert!(events.iter().any(|event| matches!(event, SessionEvent::Disconnected(DisconnectionReason::BrokerError(_)))));
+    assert!(!events.iter().any(|event| matches!(event, SessionEvent::Reconnecting { .. })),
+            "expected no reconnection after an ERROR");
+}
+
+#[test]
+fn broker_errors_are_reconnected_after_when_asked() {
+    let events = events_after_a_broker_error(ReconnectPolicy::exponential(10, 10).reconnect_on_broker_error());
+    assert!(events.iter().any(|event| matches!(event, SessionEvent::Reconnected { .. })));
+}
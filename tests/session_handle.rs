@@ -0,0 +1,135 @@
+#![cfg(feature = "broker")]
+extern crate futures;
+extern crate stomp;
+extern crate tokio_core;
+
+use std::thread;
+use futures::{future, Async, Future, Stream};
+use futures::sync::mpsc;
+use stomp::broker::Broker;
+use stomp::frame::Frame;
+use stomp::header::Header;
+use stomp::session::{Session, SessionEvent};
+use stomp::session_builder::SessionBuilder;
+use stomp::subscription::AckMode;
+use stomp::Error;
+use tokio_core::reactor::Core;
+
+fn connect(core: &mut Core, broker: &Broker) -> Session {
+    let session = SessionBuilder::new("127.0.0.1", broker.port()).start(core.handle()).unwrap();
+    match core.run(session.into_future()) {
+        Ok((Some(SessionEvent::Connected), session)) => session,
+        _ => panic!("expected to connect to the embedded broker"),
+    }
+}
+
+// Drives the session while waiting for `f` to complete, keeping any events it produces
+fn wait<F: Future>(core: &mut Core,
+                   session: &mut Session,
+                   events: &mut Vec<SessionEvent>,
+                   mut f: F)
+                   -> Result<F::Item, F::Error> {
+    core.run(future::poll_fn(|| {
+        while let Ok(Async::Ready(Some(event))) = session.poll() {
+            events.push(event);
+        }
+        f.poll()
+    }))
+}
+
+#[test]
+fn handles_send_from_other_threads() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, &broker);
+
+    let (results, finished) = mpsc::unbounded();
+    for n in 0..4 {
+        let handle = session.handle();
+        let results = results.clone();
+        thread::spawn(move || {
+            let result = handle.send("/queue/handles", format!("#{}", n)).wait();
+            results.unbounded_send(result).unwrap();
+        });
+    }
+    let mut events = vec![];
+    let sent = wait(&mut core, &mut session, &mut events, finished.take(4).collect()).unwrap();
+    assert!(sent.iter().all(|result| result.is_ok()));
+    let receipt = session.handle().send_with_receipt(Frame::send("/queue/handles", b"last"));
+    wait(&mut core, &mut session, &mut events, receipt).unwrap();
+    assert_eq!(broker.pending("/queue/handles").len(), 5);
+}
+
+#[test]
+fn receipts_from_separate_handles_do_not_collide() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, &broker);
+    let mut events = vec![];
+
+    let first = session.handle().send_with_receipt(Frame::send("/queue/handles", b"first"));
+    let second = session.handle().send_with_receipt(Frame::send("/queue/handles", b"second"));
+    assert_ne!(first.id(), second.id());
+    wait(&mut core, &mut session, &mut events, first.join(second)).unwrap();
+    assert_eq!(broker.pending("/queue/handles").len(), 2);
+}
+
+#[test]
+fn handles_manage_transactions_and_subscriptions() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, &broker);
+    let handle = session.handle();
+    let mut events = vec![];
+
+    let transaction = wait(&mut core, &mut session, &mut events, handle.begin()).unwrap();
+    let mut frame = Frame::send("/queue/handles", b"in a transaction");
+    frame.headers.push(Header::new("transaction", &transaction));
+    wait(&mut core, &mut session, &mut events, handle.send_frame(frame)).unwrap();
+    let committed = handle.commit(&transaction).and_then(|_| {
+        handle.send_with_receipt(Frame::send("/queue/other", b"after the commit"))
+    });
+    wait(&mut core, &mut session, &mut events, committed).unwrap();
+    assert_eq!(broker.pending("/queue/handles").len(), 1);
+
+    let sub_id = wait(&mut core, &mut session, &mut events, handle.subscribe("/queue/handles", AckMode::Client)).unwrap();
+    // The broker delivers the queued message before it answers anything sent afterwards
+    let receipt = handle.send_with_receipt(Frame::send("/queue/other", b"after subscribing"));
+    wait(&mut core, &mut session, &mut events, receipt).unwrap();
+    let message = events.into_iter()
+        .filter_map(|event| match event {
+            SessionEvent::Message { frame, .. } => Some(frame),
+            _ => None,
+        })
+        .next()
+        .expect("expected the transacted message to be delivered");
+    let mut events = vec![];
+    assert_eq!(message.headers.get_subscription().unwrap().0, sub_id);
+    wait(&mut core, &mut session, &mut events, handle.ack(&message)).unwrap();
+    wait(&mut core, &mut session, &mut events, handle.unsubscribe(&sub_id)).unwrap();
+    let receipt = handle.send_with_receipt(Frame::send("/queue/other", b"flush"));
+    wait(&mut core, &mut session, &mut events, receipt).unwrap();
+    assert!(broker.unacknowledged("/queue/handles").is_empty());
+    assert_eq!(broker.subscriber_count("/queue/handles"), 0);
+}
+
+#[test]
+fn handles_report_when_the_session_is_not_connected() {
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port).start(core.handle()).unwrap();
+    let mut events = vec![];
+
+    let sent = session.handle().send("/queue/handles", "nobody is listening");
+    match wait(&mut core, &mut session, &mut events, sent) {
+        Err(Error::NotConnected) => {},
+        _ => panic!("expected the send to fail"),
+    }
+    let handle = session.handle();
+    drop(session);
+    match handle.send("/queue/handles", "too late").wait() {
+        Err(Error::SessionClosed) => {},
+        _ => panic!("expected the reply to fail once the session was dropped"),
+    }
+}
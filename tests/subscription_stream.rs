@@ -0,0 +1,91 @@
+#![cfg(feature = "broker")]
+extern crate futures;
+extern crate stomp;
+extern crate tokio_core;
+
+use futures::{future, Async, Future, Stream};
+use stomp::broker::Broker;
+use stomp::session::{Session, SessionEvent};
+use stomp::session_builder::SessionBuilder;
+use stomp::subscription::{AckMode, ReceivedMessage, SubscriptionStream};
+use tokio_core::reactor::Core;
+
+fn connect(core: &mut Core, broker: &Broker) -> Session {
+    let session = SessionBuilder::new("127.0.0.1", broker.port()).start(core.handle()).unwrap();
+    match core.run(session.into_future()) {
+        Ok((Some(SessionEvent::Connected), session)) => session,
+        _ => panic!("expected to connect to the embedded broker"),
+    }
+}
+
+// Drives the session until `f` completes, keeping any events it produces
+fn wait<F: Future>(core: &mut Core,
+                   session: &mut Session,
+                   events: &mut Vec<SessionEvent>,
+                   mut f: F)
+                   -> Result<F::Item, F::Error> {
+    core.run(future::poll_fn(|| {
+        while let Ok(Async::Ready(Some(event))) = session.poll() {
+            events.push(event);
+        }
+        f.poll()
+    }))
+}
+
+fn next_message(core: &mut Core,
+                session: &mut Session,
+                events: &mut Vec<SessionEvent>,
+                stream: &mut SubscriptionStream)
+                -> ReceivedMessage {
+    match wait(core, session, events, future::poll_fn(|| stream.poll())) {
+        Ok(Some(message)) => message,
+        _ => panic!("expected the subscription to yield a message"),
+    }
+}
+
+#[test]
+fn subscription_streams_only_see_their_own_messages() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, &broker);
+    let mut events = vec![];
+
+    let mut stream = session.subscription("/queue/streamed").with(AckMode::Client).start_stream();
+    let other_id = session.subscription("/queue/evented").start();
+    session.message("/queue/evented", "to the session").send();
+    session.message("/queue/streamed", "to the stream").send();
+
+    let message = next_message(&mut core, &mut session, &mut events, &mut stream);
+    assert_eq!(message.frame.headers.get_subscription().unwrap().0, stream.id());
+    assert_eq!(message.frame.body, b"to the stream".to_vec());
+    wait(&mut core, &mut session, &mut events, message.ack()).unwrap();
+
+    let receipt = session.message("/queue/other", "flush").send_with_receipt();
+    wait(&mut core, &mut session, &mut events, receipt).unwrap();
+    assert!(broker.unacknowledged("/queue/streamed").is_empty());
+    let evented: Vec<_> = events.iter()
+        .filter_map(|event| match *event {
+            SessionEvent::Message { ref frame, .. } => Some(frame.headers.get_subscription().unwrap().0.to_owned()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(evented, vec![other_id]);
+}
+
+#[test]
+fn dropping_a_subscription_stream_unsubscribes() {
+    let broker = Broker::start().unwrap();
+    let mut core = Core::new().unwrap();
+    let mut session = connect(&mut core, &broker);
+    let mut events = vec![];
+
+    let stream = session.subscription("/queue/streamed").start_stream();
+    let receipt = session.message("/queue/other", "flush").send_with_receipt();
+    wait(&mut core, &mut session, &mut events, receipt).unwrap();
+    assert_eq!(broker.subscriber_count("/queue/streamed"), 1);
+
+    drop(stream);
+    let receipt = session.handle().send_with_receipt(stomp::frame::Frame::send("/queue/other", b"flush"));
+    wait(&mut core, &mut session, &mut events, receipt).unwrap();
+    assert_eq!(broker.subscriber_count("/queue/streamed"), 0);
+}
@@ -0,0 +1,68 @@
+#![cfg(feature = "tls")]
+extern crate futures;
+extern crate native_tls;
+extern crate stomp;
+extern crate tokio_core;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use futures::Stream;
+use native_tls::{Identity, TlsAcceptor};
+use stomp::session::{DisconnectionReason, SessionEvent};
+use stomp::session_builder::SessionBuilder;
+use stomp::tls::TlsConfig;
+use tokio_core::reactor::Core;
+
+const CA: &[u8] = include_bytes!("fixtures/ca.pem");
+const SERVER_IDENTITY: &[u8] = include_bytes!("fixtures/server.p12");
+
+// Accepts a single TLS connection and answers its CONNECT frame
+fn spawn_tls_broker() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let identity = Identity::from_pkcs12(SERVER_IDENTITY, "stomp-rs").unwrap();
+    let acceptor = TlsAcceptor::new(identity).unwrap();
+    thread::spawn(move || {
+        let (tcp, _) = listener.accept().unwrap();
+        let mut stream = match acceptor.accept(tcp) {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+        let mut byte = [0u8];
+        while stream.read(&mut byte).unwrap_or(0) == 1 && byte[0] != 0 {}
+        let _ = stream.write_all(b"CONNECTED\nversion:1.2\n\n\0");
+        let _ = stream.read(&mut byte);
+    });
+    port
+}
+
+#[test]
+fn connects_over_tls_with_custom_ca() {
+    let port = spawn_tls_broker();
+    let mut core = Core::new().unwrap();
+    let session = SessionBuilder::new("localhost", port)
+        .with(TlsConfig::new().ca_bundle(CA))
+        .start(core.handle())
+        .unwrap();
+    let (event, _session) = core.run(session.into_future()).map_err(|(e, _)| e).unwrap();
+    match event {
+        Some(SessionEvent::Connected) => {},
+        _ => panic!("expected the session to connect over TLS"),
+    }
+}
+
+#[test]
+fn rejects_untrusted_certificate() {
+    let port = spawn_tls_broker();
+    let mut core = Core::new().unwrap();
+    let session = SessionBuilder::new("localhost", port)
+        .with(TlsConfig::new())
+        .start(core.handle())
+        .unwrap();
+    let (event, _session) = core.run(session.into_future()).map_err(|(e, _)| e).unwrap();
+    match event {
+        Some(SessionEvent::Disconnected(DisconnectionReason::ConnectFailed(_))) => {},
+        _ => panic!("expected the TLS handshake to fail"),
+    }
+}
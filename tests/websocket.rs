@@ -0,0 +1,121 @@
+#![cfg(feature = "websocket")]
+extern crate futures;
+extern crate stomp;
+extern crate tokio_core;
+extern crate tungstenite;
+
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+use futures::{future, Async, Stream};
+use stomp::session::{SendBufferCapacity, SessionEvent};
+use stomp::session_builder::SessionBuilder;
+use tokio_core::reactor::Core;
+use tungstenite::Message;
+use tungstenite::handshake::server::{Request, Response};
+
+// Accepts a single WebSocket connection, reports the negotiated sub-protocol
+// and the first message received, then answers with a CONNECTED frame
+fn spawn_websocket_broker() -> (u16, mpsc::Receiver<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let (tcp, _) = listener.accept().unwrap();
+        let mut protocol = String::new();
+        let mut socket = tungstenite::accept_hdr(tcp, |request: &Request, mut response: Response| {
+            if let Some(requested) = request.headers().get("Sec-WebSocket-Protocol") {
+                protocol = requested.to_str().unwrap().to_owned();
+                response.headers_mut().insert("Sec-WebSocket-Protocol", requested.clone());
+            }
+            Ok(response)
+        }).unwrap();
+        let connect = socket.read().unwrap().into_text().unwrap();
+        socket.send(Message::Text("CONNECTED\nversion:1.2\n\n\0".to_owned())).unwrap();
+        tx.send((protocol, connect)).unwrap();
+        let _ = socket.read();
+    });
+    (port, rx)
+}
+
+#[test]
+fn connects_over_websocket() {
+    let (port, server) = spawn_websocket_broker();
+    let mut core = Core::new().unwrap();
+    let url = format!("ws://127.0.0.1:{}/ws", port);
+    let session = SessionBuilder::websocket(&url)
+        .start(core.handle())
+        .unwrap();
+    let (event, _session) = core.run(session.into_future()).map_err(|(e, _)| e).unwrap();
+    match event {
+        Some(SessionEvent::Connected) => {},
+        _ => panic!("expected the session to connect over a WebSocket"),
+    }
+    let (protocol, connect) = server.recv().unwrap();
+    assert_eq!(protocol, "v12.stomp");
+    assert!(connect.starts_with("CONNECT\n"));
+    assert!(connect.ends_with("\0"));
+}
+
+#[test]
+fn rejects_malformed_url() {
+    let core = Core::new().unwrap();
+    assert!(SessionBuilder::websocket("tcp://127.0.0.1:61613").start(core.handle()).is_err());
+}
+
+#[test]
+fn sends_wait_for_a_slow_websocket() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (drain, drain_receiver) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let (tcp, _) = listener.accept().unwrap();
+        let mut socket = tungstenite::accept(tcp).unwrap();
+        socket.read().unwrap();
+        socket.send(Message::Text("CONNECTED\nversion:1.2\n\n\0".to_owned())).unwrap();
+        // Stop reading until the client has filled its send buffer
+        drain_receiver.recv().unwrap();
+        let mut received = 0;
+        loop {
+            match socket.read() {
+                Ok(Message::Text(_)) => received += 1,
+                Ok(_) => {},
+                Err(_) => return received,
+            }
+        }
+    });
+
+    let mut core = Core::new().unwrap();
+    let url = format!("ws://127.0.0.1:{}/ws", port);
+    let mut session = SessionBuilder::websocket(&url)
+        .with(SendBufferCapacity(64 * 1024))
+        .start(core.handle())
+        .unwrap();
+    let body = vec![b'x'; 16 * 1024];
+    let mut sent = 0;
+    core.run(future::poll_fn(|| {
+        loop {
+            while let Ok(Async::Ready(Some(event))) = session.poll() {
+                if let SessionEvent::SendBufferFull { .. } = event {
+                    return Ok::<_, ()>(Async::Ready(()));
+                }
+            }
+            if session.version().is_none() || session.poll_send_ready().is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+            session.message("/queue/big", &body[..]).send();
+            sent += 1;
+        }
+    })).unwrap();
+    assert!(session.buffered_bytes() >= 64 * 1024);
+
+    drain.send(()).unwrap();
+    core.run(future::poll_fn(|| {
+        while let Ok(Async::Ready(Some(_))) = session.poll() {}
+        Ok::<_, ()>(session.poll_send_ready())
+    })).unwrap();
+    assert!(session.buffered_bytes() < 64 * 1024);
+    drop(session);
+    // Nothing was dropped while the socket was backed up
+    assert!(server.join().unwrap() <= sent);
+}